@@ -1,9 +1,9 @@
 local_entity:register({
     on_update = function()
         if (global:turn_count() % 2 == 0) then
-            -- local_entity:set_anim("up")
+            local_entity:set_anim("up")
         else
-            -- local_entity:set_anim("down")
+            local_entity:set_anim("down")
         end
     end
-})
\ No newline at end of file
+})
@@ -0,0 +1,45 @@
+-- Movement helpers shared by actor scripts, loaded with require("lib.ai")
+local ai = {}
+
+local dirs = {
+    [-1] = { [-1] = "northwest", [0] = "north", [1] = "northeast" },
+    [0]  = { [-1] = "west",                     [1] = "east" },
+    [1]  = { [-1] = "southwest", [0] = "south", [1] = "southeast" },
+}
+
+-- the direction of an adjacent cell, or nil if it isn't adjacent
+function ai.dir_to(from, to)
+    local row = dirs[to.y - from.y]
+    return row and row[to.x - from.x]
+end
+
+-- takes one step along a straight line toward a position, returning if it moved
+function ai.step_toward(entity, target)
+    local x, y, z = entity:pos()
+    if x == nil then
+        return false
+    end
+    local line = global:line({ x = x, y = y, z = z }, target)
+    local next = line[2]
+    local dir = next and ai.dir_to(line[1], next)
+    return dir ~= nil and entity:move(dir)
+end
+
+-- takes one step in a random direction that's free, returning if it moved
+function ai.wander(entity)
+    local options = {}
+    for _, row in pairs(dirs) do
+        for _, dir in pairs(row) do
+            if entity:can_move(dir) then
+                table.insert(options, dir)
+            end
+        end
+    end
+    if #options == 0 then
+        return false
+    end
+    table.sort(options) -- pairs order isn't fixed, and runs have to be reproducible
+    return entity:move(options[global:random(1, #options, "ai")])
+end
+
+return ai
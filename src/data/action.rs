@@ -1,15 +1,107 @@
 use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
 
+use crate::data::level::*;
+use crate::data::travel::*;
 use crate::util::types::*;
 
 pub const SECONDS_TO_WALK: f32 = 0.2;
 pub const SECONDS_TO_RUN: f32 = 0.1;
+pub const REST_MAX_TURNS: usize = 100;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Dir {
     North, Northeast, East, Southeast, South, Southwest, West, Northwest,
 }
 
+impl Dir {
+    /// Clockwise order
+    pub const ALL: [Dir; 8] = [Dir::North, Dir::Northeast, Dir::East, Dir::Southeast, Dir::South, Dir::Southwest, Dir::West, Dir::Northwest];
+
+    /// Rotates clockwise by 45 degree steps, or counter-clockwise if negative
+    pub fn rotate(&self, steps: i32) -> Dir {
+        let idx = Dir::ALL.iter().position(|d| d == self).unwrap() as i32;
+        Dir::ALL[(idx + steps).rem_euclid(8) as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Dir> {
+        Dir::ALL.iter().find(|d| d.name() == name).cloned()
+    }
+
+    /// How scripts refer to this direction
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dir::North     => "north",
+            Dir::Northeast => "northeast",
+            Dir::East      => "east",
+            Dir::Southeast => "southeast",
+            Dir::South     => "south",
+            Dir::Southwest => "southwest",
+            Dir::West      => "west",
+            Dir::Northwest => "northwest",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Action {
+    Move(Dir),
+    Wait,
+}
+
+impl Action {
+    /// `"wait"`, or a direction to move in
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "wait" => Some(Action::Wait),
+            _      => Dir::from_name(name).map(Action::Move),
+        }
+    }
+}
+
+/// Where an entity's actions come from. Input sources fill its `LocalActions` and gate the player's part of the turn, with
+/// a replay feeding the recorded actions back in place of them. Scripted sources take turns alongside the NPCs instead
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ActionSource {
+    Keyboard,
+    Gamepad(usize),
+    Ai,       // the entity's own script, through `local_entity:act`
+    Scripted, // other scripts, through `entity:act`, such as for a cutscene
+}
+
+impl ActionSource {
+    /// If scripts choose this entity's actions, rather than a player
+    pub fn is_scripted(&self) -> bool {
+        match self {
+            ActionSource::Ai | ActionSource::Scripted => true,
+            _                                         => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ActionOrigin {
+    Input,
+    Repeat,
+    Travel,
+}
+
+/// An action performed once per turn until it runs out or is interrupted
+#[derive(Clone, Debug)]
+pub struct Repeat {
+    pub action:    Action,
+    pub remaining: usize,
+    pub seen:      Option<Vec<Entity>>, // hostiles already in view when the repeat began
+    pub started:   f64,
+}
+
+impl Repeat {
+    pub fn new(action: Action, remaining: usize, started: f64) -> Repeat {
+        Repeat { action, remaining, seen: None, started }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LocalActions {
     pub north:      TimeStamped<bool>,
@@ -18,6 +110,12 @@ pub struct LocalActions {
     pub west:       TimeStamped<bool>,
     pub run:        TimeStamped<bool>,
     pub interact:   TimeStamped<bool>,
+    pub wait:       TimeStamped<bool>,
+    pub rest:       TimeStamped<bool>,
+    pub count:      Option<usize>,
+    pub repeat:     Option<Repeat>,
+    pub travel:     Option<Travel>,
+    pub queued:     Option<Action>, // fed in directly rather than from held inputs, such as by a replay
     pub move_timer: Timer,
 }
 
@@ -30,6 +128,12 @@ impl Default for LocalActions {
             west:     TimeStamped::default(),
             run:      TimeStamped::default(),
             interact: TimeStamped::default(),
+            wait:     TimeStamped::default(),
+            rest:     TimeStamped::default(),
+            count:    None,
+            repeat:   None,
+            travel:   None,
+            queued:   None,
             move_timer: Timer::from_seconds(SECONDS_TO_WALK, false),
         }
     }
@@ -41,29 +145,165 @@ impl LocalActions {
         let ew_comp = index_component(IDX_EAST , IDX_WEST,  &self.east,  &self.west, seconds_elapsed);
         index_to_direction(ns_comp + ew_comp)
     }
+
+    /// Updates the held state of each input, as read from this entity's `ActionSource`
+    pub fn update_held(&mut self, timestamp: f64, held: HeldInputs) {
+        self.north.update(timestamp, held.north);
+        self.south.update(timestamp, held.south);
+        self.east .update(timestamp, held.east);
+        self.west .update(timestamp, held.west);
+        self.run  .update(timestamp, held.run);
+        self.wait .update(timestamp, held.wait);
+        self.rest .update(timestamp, held.rest);
+    }
+
+    /// Appends a digit to the count prefix for the next action
+    pub fn push_count_digit(&mut self, digit: usize) {
+        self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+    }
+
+    /// Rests until interrupted, or for the count prefix's number of turns if one was typed
+    pub fn start_rest(&mut self, seconds_elapsed: f64) {
+        let remaining = self.count.take().unwrap_or(REST_MAX_TURNS);
+        self.travel = None;
+        self.repeat = Some(Repeat::new(Action::Wait, remaining, seconds_elapsed));
+    }
+
+    pub fn start_travel(&mut self, path: VecDeque<Pos>, seconds_elapsed: f64) {
+        self.cancel();
+        self.travel = Some(Travel::path(path, seconds_elapsed));
+    }
+
+    pub fn cancel(&mut self) {
+        self.count  = None;
+        self.repeat = None;
+        self.travel = None;
+    }
+
+    /// The action to take this turn and where it came from; a count prefix turns the next action into a repeat, and running starts travel
+    pub fn next_action(&mut self, pos: &Pos, seconds_elapsed: f64) -> Option<(Action, ActionOrigin)> {
+        if let Some(action) = self.queued.take() {
+            return Some((action, ActionOrigin::Input));
+        }
+        let input = if let Some(dir) = self.dir(seconds_elapsed) {
+            Some(Action::Move(dir))
+        } else if self.wait.value {
+            Some(Action::Wait)
+        } else {
+            None
+        };
+
+        if let Some(action) = input {
+            // keys still held from before a repeat or travel started don't cancel it, but new presses do
+            let started = self.repeat.as_ref().map(|r| r.started).or_else(|| self.travel.as_ref().map(|t| t.started));
+            if started.map_or(true, |s| self.latest_press() > s) {
+                self.repeat = None;
+                self.travel = None;
+                match (action, self.count.take()) {
+                    (Action::Move(dir), _) if self.run.value => self.travel = Some(Travel::run(dir, seconds_elapsed)),
+                    (_, Some(count)) if count > 1 => self.repeat = Some(Repeat::new(action, count, seconds_elapsed)),
+                    _ => return Some((action, ActionOrigin::Input)),
+                }
+            }
+        }
+        if let Some(travel) = self.travel.as_ref() {
+            match travel.next_dir(pos) {
+                Some(dir) => return Some((Action::Move(dir), ActionOrigin::Travel)),
+                None      => self.travel = None,
+            }
+        }
+        // a finished repeat is only dropped on the next call, so its last step can still be checked against what it's seen
+        match self.repeat.as_mut() {
+            Some(repeat) if repeat.remaining > 0 => {
+                repeat.remaining -= 1;
+                Some((repeat.action, ActionOrigin::Repeat))
+            },
+            _ => {
+                self.repeat = None;
+                None
+            },
+        }
+    }
+
+    fn latest_press(&self) -> f64 {
+        [&self.north, &self.south, &self.east, &self.west, &self.wait].iter()
+            .filter(|t| t.value)
+            .map(|t| t.timestamp)
+            .fold(f64::MIN, f64::max)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeldInputs {
+    pub north: bool,
+    pub south: bool,
+    pub east:  bool,
+    pub west:  bool,
+    pub run:   bool,
+    pub wait:  bool,
+    pub rest:  bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct ControlSettings {
-    pub north: KeyCode,
-    pub south: KeyCode,
-    pub east:  KeyCode,
-    pub west:  KeyCode,
-    pub run:   KeyCode,
+    pub north:       KeyCode,
+    pub south:       KeyCode,
+    pub east:        KeyCode,
+    pub west:        KeyCode,
+    pub run:         KeyCode,
+    pub wait:        KeyCode,
+    pub rest:        KeyCode,
+    pub cancel:      KeyCode,
+    pub undo:        KeyCode,
+    pub redo:        KeyCode,
+    pub pause:       KeyCode,
+    pub dismiss:     KeyCode, // hides the script error overlay
+    pub pad_run:     GamepadButtonType,
+    pub pad_wait:    GamepadButtonType,
+    pub pad_rest:    GamepadButtonType,
+    pub pad_cancel:  GamepadButtonType,
+    pub pad_undo:    GamepadButtonType,
+    pub pad_redo:    GamepadButtonType,
+    pub pad_pause:   GamepadButtonType,
+    pub pad_dismiss: GamepadButtonType,
 }
 
 impl Default for ControlSettings {
     fn default() -> Self {
         ControlSettings {
-            north: KeyCode::W,
-            south: KeyCode::S,
-            east:  KeyCode::D,
-            west:  KeyCode::A,
-            run:   KeyCode::LShift,
+            north:       KeyCode::W,
+            south:       KeyCode::S,
+            east:        KeyCode::D,
+            west:        KeyCode::A,
+            run:         KeyCode::LShift,
+            wait:        KeyCode::Period,
+            rest:        KeyCode::R,
+            cancel:      KeyCode::Escape,
+            undo:        KeyCode::Z,
+            redo:        KeyCode::Y,
+            pause:       KeyCode::Space,
+            dismiss:     KeyCode::F1,
+            pad_run:     GamepadButtonType::RightTrigger,
+            pad_wait:    GamepadButtonType::South,
+            pad_rest:    GamepadButtonType::West,
+            pad_cancel:  GamepadButtonType::East,
+            pad_undo:    GamepadButtonType::LeftTrigger,
+            pad_redo:    GamepadButtonType::LeftTrigger2,
+            pad_pause:   GamepadButtonType::Start,
+            pad_dismiss: GamepadButtonType::Select,
         }
     }
 }
 
+/// How far a stick must be pushed to count as a held direction
+pub const STICK_DEADZONE: f32 = 0.5;
+
+/// Keys for typing a count prefix, indexed by their digit
+pub const COUNT_KEYS: [KeyCode; 10] = [
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+];
+
 // Private
 
 const INPUT_DELAY_SECONDS: f64 = 5. / 60.; // 5 frames
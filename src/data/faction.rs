@@ -0,0 +1,12 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum Faction {
+    Neutral,
+    Ally,
+    Hostile,
+}
+
+impl Default for Faction {
+    fn default() -> Self { Faction::Neutral }
+}
@@ -0,0 +1,123 @@
+use bevy::prelude::*;
+use std::collections::{HashMap, VecDeque};
+
+use crate::data::level::*;
+use crate::data::random::*;
+use crate::data::sprite::*;
+use crate::data::turn::*;
+use crate::lua::*;
+
+pub const DEFAULT_HISTORY_DEPTH: usize = 50;
+
+/// The state of the world at the start of a turn
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub turn_count: usize,
+    pub grids:      HashMap<Entity, Grid>,
+    pub positions:  HashMap<Entity, Pos>,
+    pub anims:      HashMap<Entity, AnimState>,
+    pub energies:   HashMap<Entity, Energy>,
+    pub random:     StreamStates, // so a replay recorded around an undo draws the same numbers as one without it
+    pub lua:        LuaSnapshot,
+}
+
+/// Snapshots of the last `depth` turns that can be stepped back through, and forward again until a new turn is taken
+pub struct History {
+    pub depth: usize,
+    past:      VecDeque<Snapshot>, // the newest is the turn currently being played
+    future:    Vec<Snapshot>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_HISTORY_DEPTH)
+    }
+}
+
+impl History {
+    pub fn new(depth: usize) -> History {
+        History { depth, past: VecDeque::new(), future: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.past.is_empty()
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.future.clear();
+        self.past.push_back(snapshot);
+        while self.past.len() > self.depth + 1 {
+            self.past.pop_front();
+        }
+    }
+
+    /// Returns the snapshot to restore to go back a turn
+    pub fn undo(&mut self) -> Option<&Snapshot> {
+        if self.past.len() < 2 {
+            return None;
+        }
+        self.future.push(self.past.pop_back().unwrap());
+        self.past.back()
+    }
+
+    /// Returns the snapshot to restore to go forward a turn again
+    pub fn redo(&mut self) -> Option<&Snapshot> {
+        let snapshot = self.future.pop()?;
+        self.past.push_back(snapshot);
+        self.past.back()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(turn_count: usize) -> Snapshot {
+        Snapshot {
+            turn_count,
+            grids:     HashMap::new(),
+            positions: HashMap::new(),
+            anims:     HashMap::new(),
+            energies:  HashMap::new(),
+            random:    StreamStates::new(),
+            lua:       LuaSnapshot::default(),
+        }
+    }
+
+    fn history_of(depth: usize, turns: usize) -> History {
+        let mut history = History::new(depth);
+        for turn in 0..turns {
+            history.push(snapshot(turn));
+        }
+        history
+    }
+
+    #[test]
+    fn undo_steps_back_and_redo_steps_forward() {
+        let mut history = history_of(5, 3);
+        assert_eq!(history.undo().map(|s| s.turn_count), Some(1));
+        assert_eq!(history.undo().map(|s| s.turn_count), Some(0));
+        assert_eq!(history.undo().map(|s| s.turn_count), None);
+        assert_eq!(history.redo().map(|s| s.turn_count), Some(1));
+        assert_eq!(history.redo().map(|s| s.turn_count), Some(2));
+        assert_eq!(history.redo().map(|s| s.turn_count), None);
+    }
+
+    #[test]
+    fn a_new_turn_drops_the_redos() {
+        let mut history = history_of(5, 3);
+        history.undo();
+        history.push(snapshot(2));
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo().map(|s| s.turn_count), Some(1));
+    }
+
+    #[test]
+    fn only_depth_turns_can_be_undone() {
+        let mut history = history_of(2, 5);
+        assert_eq!(history.undo().map(|s| s.turn_count), Some(3));
+        assert_eq!(history.undo().map(|s| s.turn_count), Some(2));
+        assert_eq!(history.undo().map(|s| s.turn_count), None);
+    }
+}
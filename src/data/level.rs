@@ -2,9 +2,14 @@ use bevy::{
     prelude::*,
     reflect::TypeUuid,
 };
+use enumset::*;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
 use crate::data::action::*;
+use crate::data::sprite::TILE_SIZE;
+
+pub const VIEW_RADIUS: i32 = 8;
 
 #[derive(Clone, Debug, Default)]
 pub struct MapScale(pub f32);
@@ -15,6 +20,7 @@ pub struct LevelInfo {
     pub title: String,
     pub subtitle: Option<String>,
     pub level_idx: usize,
+    pub bg_color: Color,
 }
 
 #[derive(TypeUuid)]
@@ -26,7 +32,15 @@ pub struct LevelToLoad(pub usize);
 #[uuid = "8bf0327e-2d5c-42ef-b614-f883ae078b0b"]
 pub struct OwningLevel(pub Entity);
 
-#[derive(Clone, Copy, Debug, Default)]
+/// Sent when a tile's state changes after the level has loaded, for anything that caches paths or what's visible
+#[derive(Clone, Debug)]
+pub struct GridChanged {
+    pub level: Entity,
+    pub pos:   Pos,
+    pub state: PosState,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Pos {
     pub x: i32,
     pub y: i32, // +y is down, unlike in Bevy rendering
@@ -46,14 +60,73 @@ impl Pos {
             Dir::Northwest => Pos { y: self.y - 1, x: self.x - 1, ..self.clone()},
         }
     }
+
+    /// The direction of an adjacent position on the same layer
+    pub fn dir_to(&self, other: &Pos) -> Option<Dir> {
+        if self.z != other.z {
+            return None;
+        }
+        Dir::ALL.iter().cloned().find(|d| self.step(d.clone()) == *other)
+    }
+
+    /// Inverse of `translation`, for a point anywhere within the tile
+    pub fn from_translation(translation: Vec3, map_scale: &MapScale, z: i32) -> Pos {
+        Pos {
+            x: (translation.x / (TILE_SIZE * map_scale.0)).floor() as i32,
+            y: (-translation.y / (TILE_SIZE * map_scale.0)).floor() as i32,
+            z,
+        }
+    }
+
+    /// Cells along a Bresenham line from this position to `other`, including both ends; stays on this position's layer.
+    /// Always traced from the lower of the two ends, so going the other way passes through the same cells
+    pub fn line_to(&self, other: &Pos) -> Vec<Pos> {
+        if (other.x, other.y) < (self.x, self.y) {
+            let mut cells = Pos { z: self.z, ..other.clone() }.line_to(self);
+            cells.reverse();
+            return cells;
+        }
+        let (dx, dy) = ((other.x - self.x).abs(), -(other.y - self.y).abs());
+        let (sx, sy) = ((other.x - self.x).signum(), (other.y - self.y).signum());
+        let mut err = dx + dy;
+        let mut cur = self.clone();
+        let mut cells = vec![cur];
+        while cur.x != other.x || cur.y != other.y {
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                cur.x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                cur.y += sy;
+            }
+            cells.push(cur);
+        }
+        cells
+    }
+
+    /// Chebyshev distance, ignoring layers
+    pub fn distance(&self, other: &Pos) -> i32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs())
+    }
+
+    /// Center of this tile in world space
+    pub fn translation(&self, map_scale: &MapScale) -> Vec3 {
+        Vec3::new(
+            map_scale.0 * (TILE_SIZE *  self.x as f32 + 0.5 * TILE_SIZE),
+            map_scale.0 * (TILE_SIZE * -self.y as f32 - 0.5 * TILE_SIZE),
+            self.z as f32,
+        )
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PosState {
     None,
     Solid,
     Floorless,
-    Entity(Entity),
+    Water,
     //Item(Handle<Todo>),
     Damaging(f32),
 }
@@ -63,28 +136,72 @@ impl Default for PosState {
 }
 
 impl PosState {
-    pub fn is_blocking(&self) -> bool {
+    pub fn is_blocking_for(&self, movement: EnumSet<Movement>) -> bool {
         match self {
             PosState::None | PosState::Damaging(_) => false,
-            _ => true,
+            PosState::Solid     => !movement.contains(Movement::Ethereal),
+            PosState::Floorless => !movement.contains(Movement::Flying),
+            PosState::Water     => (movement & (Movement::Flying | Movement::Swimming)).is_empty(),
         }
     }
+
+    pub fn is_blocking_sight(&self) -> bool {
+        match self {
+            PosState::Solid => true,
+            _               => false,
+        }
+    }
+}
+
+/// Movement capabilities of an entity, declared by its prefab
+#[derive(Debug, Deserialize, EnumSetType, Serialize)]
+#[enumset(serialize_as_list)]
+pub enum Movement {
+    Flying,   // ignores Floorless and Water
+    Ethereal, // passes through Solid
+    Swimming, // passes through Water
+    Small,    // can share a cell with other Small entities
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Occupant {
+    pub entity:   Entity,
+    pub movement: EnumSet<Movement>,
 }
 
-#[derive(Debug, TypeUuid)]
+#[derive(Clone, Debug, TypeUuid)]
 #[uuid = "2b16de55-c777-41ea-a05b-e62c4a5e1b46"]
-pub struct Grid(pub Vec<Vec<Vec<PosState>>>);
+pub struct Grid {
+    tiles:     Vec<Vec<Vec<PosState>>>,
+    occupants: HashMap<Pos, Vec<Occupant>>,
+}
 
 impl Grid {
     pub fn new(width: usize, height: usize, layers: usize) -> Grid {
-        Grid(vec![vec![vec![PosState::None; width]; height]; layers])
+        Grid {
+            tiles:     vec![vec![vec![PosState::None; width]; height]; layers],
+            occupants: HashMap::new(),
+        }
+    }
+
+    /// Width, height and number of layers
+    pub fn dimensions(&self) -> (usize, usize, usize) {
+        let height = self.tiles.first().map_or(0, |layer| layer.len());
+        let width  = self.tiles.first().and_then(|layer| layer.first()).map_or(0, |row| row.len());
+        (width, height, self.tiles.len())
+    }
+
+    /// If a position is inside the level
+    pub fn contains(&self, pos: &Pos) -> bool {
+        let (width, height, layers) = self.dimensions();
+        pos.x >= 0 && pos.y >= 0 && pos.z >= 0 && (pos.x as usize) < width && (pos.y as usize) < height && (pos.z as usize) < layers
     }
 
     pub fn get(&self, pos: &Pos) -> PosState {
         if pos.x < 0 || pos.y < 0 || pos.z < 0 {
             PosState::default()
         } else {
-            if let Some(layer) = self.0.get(pos.z as usize) {
+            if let Some(layer) = self.tiles.get(pos.z as usize) {
                 if let Some(row) = layer.get(pos.y as usize) {
                     row.get(pos.x as usize)
                         .map(|s| s.clone())
@@ -100,7 +217,7 @@ impl Grid {
 
     pub fn set(&mut self, pos: &Pos, state: PosState) {
         if !(pos.x < 0 || pos.y < 0 || pos.z < 0) {
-            if let Some(layer) = self.0.get_mut(pos.z as usize) {
+            if let Some(layer) = self.tiles.get_mut(pos.z as usize) {
                 if let Some(row) = layer.get_mut(pos.y as usize) {
                     let len = row.len() as i32;
                     if pos.x >= 0 && pos.x < len {
@@ -112,4 +229,113 @@ impl Grid {
             println!("Attempted to set PosState in grid with negative coordinates {:?}", pos);
         }
     }
-}
\ No newline at end of file
+
+    /// Tiles whose state differs in `other`, with their state there
+    pub fn changed_tiles(&self, other: &Grid) -> Vec<(Pos, PosState)> {
+        let mut changed = Vec::new();
+        for (z, layer) in other.tiles.iter().enumerate() {
+            for (y, row) in layer.iter().enumerate() {
+                for (x, state) in row.iter().enumerate() {
+                    let pos = Pos { x: x as i32, y: y as i32, z: z as i32 };
+                    if self.get(&pos) != *state {
+                        changed.push((pos, state.clone()));
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    pub fn occupants(&self, pos: &Pos) -> &[Occupant] {
+        self.occupants.get(pos).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn occupied_cells(&self) -> impl Iterator<Item = (&Pos, &[Occupant])> {
+        self.occupants.iter().map(|(pos, occupants)| (pos, occupants.as_slice()))
+    }
+
+    pub fn add_occupant(&mut self, pos: &Pos, entity: Entity, movement: EnumSet<Movement>) {
+        self.occupants.entry(pos.clone())
+            .or_insert_with(|| Vec::new())
+            .push(Occupant { entity, movement });
+    }
+
+    pub fn remove_occupant(&mut self, pos: &Pos, entity: Entity) {
+        if let Some(occupants) = self.occupants.get_mut(pos) {
+            occupants.retain(|o| o.entity != entity);
+            if occupants.is_empty() {
+                self.occupants.remove(pos);
+            }
+        }
+    }
+
+    /// Updates the movement of an entity already in the grid, such as once its prefab has loaded
+    pub fn set_movement(&mut self, pos: &Pos, entity: Entity, movement: EnumSet<Movement>) {
+        if let Some(occupants) = self.occupants.get_mut(pos) {
+            for occupant in occupants.iter_mut().filter(|o| o.entity == entity) {
+                occupant.movement = movement;
+            }
+        }
+    }
+
+    pub fn can_enter(&self, pos: &Pos, movement: EnumSet<Movement>) -> bool {
+        if self.get(pos).is_blocking_for(movement) {
+            false
+        } else {
+            let occupants = self.occupants(pos);
+            occupants.is_empty() || (movement.contains(Movement::Small) && occupants.iter().all(|o| o.movement.contains(Movement::Small)))
+        }
+    }
+
+    pub fn has_line_of_sight(&self, from: &Pos, to: &Pos) -> bool {
+        let line = from.line_to(to);
+        line.iter()
+            .skip(1)
+            .take(line.len().saturating_sub(2))
+            .all(|p| !self.get(p).is_blocking_sight())
+    }
+
+    /// If `to` is on the same layer, within the view radius and not hidden behind walls
+    pub fn can_see(&self, from: &Pos, to: &Pos) -> bool {
+        from.z == to.z && from.distance(to) <= VIEW_RADIUS && self.has_line_of_sight(from, to)
+    }
+
+    /// Moves an entity one step if the target cell allows its movement, returning its new position
+    pub fn try_move(&mut self, entity: Entity, from: &Pos, dir: Dir, movement: EnumSet<Movement>) -> Option<Pos> {
+        let target = from.step(dir);
+        if self.can_enter(&target, movement) {
+            self.remove_occupant(from, entity);
+            self.add_occupant(&target, entity, movement);
+            Some(target)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: i32, y: i32) -> Pos {
+        Pos { x, y, z: 0 }
+    }
+
+    #[test]
+    fn lines_include_both_ends() {
+        assert_eq!(pos(1, 1).line_to(&pos(1, 1)), vec![pos(1, 1)]);
+        assert_eq!(pos(0, 0).line_to(&pos(3, 0)), vec![pos(0, 0), pos(1, 0), pos(2, 0), pos(3, 0)]);
+        assert_eq!(pos(0, 0).line_to(&pos(4, 3)), vec![pos(0, 0), pos(1, 1), pos(2, 2), pos(3, 2), pos(4, 3)]);
+    }
+
+    #[test]
+    fn lines_are_the_same_both_ways() {
+        for x in -5..=5 {
+            for y in -5..=5 {
+                let mut back = pos(x, y).line_to(&pos(0, 0));
+                back.reverse();
+                assert_eq!(pos(0, 0).line_to(&pos(x, y)), back, "line to ({}, {})", x, y);
+            }
+        }
+    }
+}
@@ -1,8 +1,15 @@
 pub mod action;
 pub mod color;
+pub mod faction;
+pub mod history;
 pub mod item;
 pub mod level;
 pub mod player;
 pub mod prefab;
+pub mod random;
+pub mod replay;
+pub mod resolve;
 pub mod sprite;
-pub mod turn;
\ No newline at end of file
+pub mod travel;
+pub mod turn;
+pub mod tween;
\ No newline at end of file
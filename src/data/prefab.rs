@@ -4,23 +4,43 @@ use bevy::{
     reflect::TypeUuid,
     utils::BoxedFuture,
 };
+use enumset::*;
 use serde::{Serialize, Deserialize};
 
+use crate::data::action::*;
+use crate::data::faction::*;
+use crate::data::level::*;
+use crate::data::resolve::*;
 use crate::data::sprite::*;
 use crate::lua::*;
 use crate::util::types::*;
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct PrefabConfig {
-    pub sprite: SpriteConfig,
-    pub script: Option<Embeddable<String>>,
+    pub sprite:   SpriteConfig,
+    pub script:   Option<Embeddable<String>>,
+    #[serde(default)]
+    pub movement: EnumSet<Movement>,
+    #[serde(default)]
+    pub faction:  Faction,
+    #[serde(default)]
+    pub speed:    Option<i32>, // only actors have a speed, and take turns
+    #[serde(default)]
+    pub priority: i32,         // higher goes first when moves conflict
+    #[serde(default)]
+    pub source:   Option<ActionSource>, // takes turns like a player with an input source, or through `act` with a scripted one
 }
 
 #[derive(Clone, Debug, TypeUuid)]
 #[uuid = "ae3e9e0a-0f2c-4acb-a959-8becff99b7e1"]
 pub struct Prefab {
-    pub sprite: Handle<SpriteInfo>,
-    pub script: Option<Handle<LuaScript>>,
+    pub sprite:   Handle<SpriteInfo>,
+    pub script:   Option<Handle<LuaScript>>,
+    pub movement: EnumSet<Movement>,
+    pub faction:  Faction,
+    pub speed:    Option<i32>,
+    pub priority: MovePriority,
+    pub source:   Option<ActionSource>,
 }
 
 #[derive(Clone, Debug)]
@@ -29,6 +49,10 @@ pub struct PrefabToSpawn {
     pub translation: Vec3,
 }
 
+/// Marks an entity to be despawned once its `on_destroy` handlers have run
+#[derive(Clone, Copy, Debug)]
+pub struct ToDespawn;
+
 #[derive(Default)]
 pub struct PrefabLoader;
 
@@ -46,7 +70,8 @@ impl AssetLoader for PrefabLoader {
                 if let Some(embeddable) = prefab_config.script {
                     match embeddable {
                         Embeddable::Embedded(s) => {
-                            Some(load_context.set_labeled_asset("embedded_script", LoadedAsset::new(LuaScript(s.into_bytes()))))
+                            let path = format!("{}#embedded_script", load_context.path().to_string_lossy());
+                            Some(load_context.set_labeled_asset("embedded_script", LoadedAsset::new(LuaScript { path, source: s.into_bytes() })))
                         },
                         Embeddable::File(f) => {
                             let path = AssetPath::new(load_context.path().parent().unwrap().join(f), None);
@@ -60,8 +85,13 @@ impl AssetLoader for PrefabLoader {
             };
 
             load_context.set_default_asset(LoadedAsset::new(Prefab {
-                sprite: sprite_handle,
+                sprite:   sprite_handle,
                 script,
+                movement: prefab_config.movement,
+                faction:  prefab_config.faction,
+                speed:    prefab_config.speed,
+                priority: MovePriority(prefab_config.priority),
+                source:   prefab_config.source,
             }).with_dependencies(dependencies));
             Ok(())
         })
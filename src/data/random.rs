@@ -0,0 +1,130 @@
+use rand::Rng;
+use rand_pcg::Pcg32;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::hash::*;
+
+pub const MAP_STREAM: &'static str    = "map";
+pub const COMBAT_STREAM: &'static str = "combat";
+pub const AI_STREAM: &'static str     = "ai";
+pub const SCRIPT_STREAM: &'static str = "script";
+
+/// The streams scripts can draw from, by name. Anything else is more likely a typo than a new purpose
+pub const STREAMS: [&'static str; 4] = [MAP_STREAM, COMBAT_STREAM, AI_STREAM, SCRIPT_STREAM];
+
+/// Where each stream is up to, so they can be rewound with the rest of the world
+pub type StreamStates = HashMap<String, Pcg32>;
+
+/// Randomness for a whole run, reproducible from its seed. Each named stream is independent, so drawing more numbers
+/// for one purpose (say, an extra AI roll) doesn't change what any other stream produces. Clones share the same streams
+#[derive(Clone)]
+pub struct RandomStreams {
+    pub seed: u64,
+    streams:  Arc<Mutex<HashMap<String, Pcg32>>>,
+}
+
+impl Default for RandomStreams {
+    fn default() -> RandomStreams {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        RandomStreams::new(nanos)
+    }
+}
+
+impl RandomStreams {
+    pub fn new(seed: u64) -> RandomStreams {
+        RandomStreams { seed, streams: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Runs `f` with the generator for a stream, creating it on first use
+    pub fn with_stream<T, F: FnOnce(&mut Pcg32) -> T>(&self, stream: &str, f: F) -> T {
+        let mut streams = self.streams.lock().unwrap();
+        let seed = self.seed;
+        let rng = streams.entry(stream.to_string()).or_insert_with(|| Pcg32::new(seed, stream_id(stream)));
+        f(rng)
+    }
+
+    pub fn save(&self) -> StreamStates {
+        self.streams.lock().unwrap().clone()
+    }
+
+    /// Puts every stream back where it was when saved, including for every clone
+    pub fn restore(&self, states: &StreamStates) {
+        *self.streams.lock().unwrap() = states.clone();
+    }
+
+    /// A whole number from `low` to `high`, inclusive
+    pub fn range(&self, stream: &str, low: i64, high: i64) -> i64 {
+        if low >= high {
+            return low;
+        }
+        self.with_stream(stream, |rng| rng.gen_range(low..=high))
+    }
+
+    /// A number from 0 up to, but not including, 1
+    pub fn fraction(&self, stream: &str) -> f64 {
+        self.with_stream(stream, |rng| rng.gen::<f64>())
+    }
+
+    /// True with probability `p`
+    pub fn chance(&self, stream: &str, p: f64) -> bool {
+        self.with_stream(stream, |rng| rng.gen_bool(p.max(0.).min(1.)))
+    }
+}
+
+fn stream_id(name: &str) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(name.as_bytes());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(random: &RandomStreams, stream: &str, count: usize) -> Vec<i64> {
+        (0..count).map(|_| random.range(stream, 1, 1000)).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        assert_eq!(draw(&RandomStreams::new(42), SCRIPT_STREAM, 20), draw(&RandomStreams::new(42), SCRIPT_STREAM, 20));
+        assert_ne!(draw(&RandomStreams::new(42), SCRIPT_STREAM, 20), draw(&RandomStreams::new(43), SCRIPT_STREAM, 20));
+    }
+
+    #[test]
+    fn streams_are_independent() {
+        let untouched = RandomStreams::new(42);
+        let expected = draw(&untouched, COMBAT_STREAM, 20);
+
+        let interleaved = RandomStreams::new(42);
+        let mut combat = Vec::new();
+        for _ in 0..20 {
+            draw(&interleaved, AI_STREAM, 3);
+            interleaved.fraction(MAP_STREAM);
+            combat.extend(draw(&interleaved, COMBAT_STREAM, 1));
+        }
+        assert_eq!(combat, expected);
+        assert_ne!(draw(&RandomStreams::new(42), AI_STREAM, 20), expected);
+    }
+
+    #[test]
+    fn restoring_rewinds_every_stream() {
+        let random = RandomStreams::new(42);
+        draw(&random, AI_STREAM, 5);
+        let saved = random.save();
+        let expected = (draw(&random, AI_STREAM, 5), draw(&random, MAP_STREAM, 5));
+        random.clone().restore(&saved);
+        assert_eq!((draw(&random, AI_STREAM, 5), draw(&random, MAP_STREAM, 5)), expected);
+    }
+
+    #[test]
+    fn clones_share_streams() {
+        let random = RandomStreams::new(7);
+        let copy = random.clone();
+        let taken_in_turns = vec![random.range(SCRIPT_STREAM, 1, 1000), copy.range(SCRIPT_STREAM, 1, 1000)];
+        assert_eq!(taken_in_turns, draw(&RandomStreams::new(7), SCRIPT_STREAM, 2));
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::data::action::*;
+use crate::lua::vars::*;
+
+pub const REPLAY_PATH: &'static str = "replays/last.replay.ron";
+
+/// An action taken by a controlled entity on a given turn
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ReplayStep {
+    pub turn:   usize,
+    pub source: ActionSource,
+    pub action: Action,
+}
+
+/// Everything needed to play a run back: the seed and variables it started with, every action in order, and a checksum of
+/// where it ended up
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Replay {
+    pub seed:     u64,
+    #[serde(default)]
+    pub vars:     SavedVars, // script variables at the start, if the run continued from a save
+    pub steps:    Vec<ReplayStep>,
+    pub checksum: u64,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> anyhow::Result<Replay> {
+        Ok(ron::de::from_bytes::<Replay>(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())?)?;
+        Ok(())
+    }
+}
+
+/// Writes the current run to `path` as it's played
+pub struct ReplayRecorder {
+    pub path:   PathBuf,
+    pub replay: Replay,
+    pub undone: Vec<ReplayStep>, // taken back by undoing, and put back if they're redone
+}
+
+impl ReplayRecorder {
+    pub fn new(path: PathBuf, replay: Replay) -> ReplayRecorder {
+        ReplayRecorder { path, replay, undone: Vec::new() }
+    }
+
+    /// Taking a new step means the undone ones can't be redone any more
+    pub fn record(&mut self, step: ReplayStep) {
+        self.undone.clear();
+        self.replay.steps.push(step);
+    }
+
+    /// Keeps only the steps taken before `turn`, after undoing or redoing back to the start of it
+    pub fn seek(&mut self, turn: usize) {
+        let mut steps = std::mem::take(&mut self.replay.steps);
+        steps.append(&mut self.undone);
+        let idx = steps.iter().position(|step| step.turn >= turn).unwrap_or(steps.len());
+        self.undone = steps.split_off(idx);
+        self.replay.steps = steps;
+    }
+}
+
+/// Feeds a recorded run back through `LocalActions` instead of reading input
+pub struct ReplayPlayer {
+    pub replay:  Replay,
+    pub next:    usize, // index of the next step to feed in
+    pub is_fast: bool,  // take each turn as soon as possible, instead of at walking pace
+}
+
+impl ReplayPlayer {
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.replay.steps.len()
+    }
+}
@@ -0,0 +1,355 @@
+use bevy::prelude::*;
+use enumset::*;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use crate::data::action::*;
+use crate::data::faction::*;
+use crate::data::level::*;
+
+/// Who wins when several entities want the same cell on the same turn; ties go to the oldest entity
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct MovePriority(pub i32);
+
+#[derive(Clone, Copy, Debug)]
+pub struct MoveReq {
+    pub entity:   Entity,
+    pub from:     Pos,
+    pub dir:      Dir,
+    pub movement: EnumSet<Movement>,
+    pub faction:  Faction,
+    pub priority: MovePriority,
+}
+
+impl MoveReq {
+    pub fn target(&self) -> Pos {
+        self.from.step(self.dir)
+    }
+}
+
+/// Why a move didn't happen, and who was in the way if anyone
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MoveDenial {
+    Blocked,           // the terrain doesn't allow the entity's movement
+    Contested(Entity), // someone with a higher priority moved there instead
+    Occupied(Entity),  // someone is there and isn't leaving
+}
+
+impl MoveDenial {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MoveDenial::Blocked      => "blocked",
+            MoveDenial::Contested(_) => "contested",
+            MoveDenial::Occupied(_)  => "occupied",
+        }
+    }
+
+    pub fn other(&self) -> Option<Entity> {
+        match self {
+            MoveDenial::Blocked => None,
+            MoveDenial::Contested(e) | MoveDenial::Occupied(e) => Some(e.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MoveResolution {
+    pub approved: Vec<(Entity, Pos)>,
+    pub denied:   Vec<(MoveReq, MoveDenial)>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    Pending,
+    Approved,
+    Denied,
+}
+
+impl Grid {
+    /// Moves every entity that asked to move this turn as if they all moved at once, so the outcome doesn't depend on
+    /// the order requests were made in. Entities can follow each other in a line, and allies can swap or rotate places
+    pub fn resolve_moves(&mut self, mut reqs: Vec<MoveReq>) -> MoveResolution {
+        reqs.sort_by_key(|req| (Reverse(req.priority), req.entity.id()));
+        let movers: HashMap<Entity, usize> = reqs.iter().enumerate().map(|(i, req)| (req.entity, i)).collect();
+        let mut status  = vec![Status::Pending; reqs.len()];
+        let mut denials = vec![None; reqs.len()];
+
+        // everyone heading for each cell, highest priority first. Who gets it is only settled once the ones ahead of
+        // them are, so losing to a move that's denied later doesn't count
+        let mut candidates: HashMap<Pos, Vec<usize>> = HashMap::new();
+        for (i, req) in reqs.iter().enumerate() {
+            let target = req.target();
+            if self.get(&target).is_blocking_for(req.movement) {
+                status[i]  = Status::Denied;
+                denials[i] = Some(MoveDenial::Blocked);
+            } else {
+                candidates.entry(target).or_insert_with(|| Vec::new()).push(i);
+            }
+        }
+
+        loop {
+            // settle everything that doesn't depend on a cycle: entering an empty cell, or one being left
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for i in 0..reqs.len() {
+                    if status[i] != Status::Pending {
+                        continue;
+                    }
+                    let req = &reqs[i];
+                    let claimants = claimants(&candidates[&req.target()], &reqs, &status);
+                    if !claimants.contains(&i) {
+                        if let Some(winner) = claimants.iter().find(|j| status[**j] == Status::Approved) {
+                            status[i]  = Status::Denied;
+                            denials[i] = Some(MoveDenial::Contested(reqs[*winner].entity));
+                            changed = true;
+                        }
+                        continue;
+                    }
+                    let mut is_waiting = false;
+                    let mut blocker = None;
+                    for occupant in self.occupants(&req.target()).iter().filter(|o| o.entity != req.entity) {
+                        match movers.get(&occupant.entity).map(|j| status[j.clone()]) {
+                            Some(Status::Approved) => (),
+                            Some(Status::Pending)  => is_waiting = true,
+                            _ => if !(req.movement.contains(Movement::Small) && occupant.movement.contains(Movement::Small)) {
+                                blocker = Some(occupant.entity);
+                            },
+                        }
+                    }
+                    if let Some(blocker) = blocker {
+                        status[i]  = Status::Denied;
+                        denials[i] = Some(MoveDenial::Occupied(blocker));
+                        changed = true;
+                    } else if !is_waiting {
+                        status[i] = Status::Approved;
+                        changed = true;
+                    }
+                }
+            }
+
+            // whatever is left is waiting on a cycle of entities stepping into each other's cells, or on a claim
+            let start = match status.iter().position(|s| *s == Status::Pending) {
+                Some(start) => start,
+                None        => break,
+            };
+            let mut path = vec![start];
+            let mut seen: HashSet<usize> = path.iter().cloned().collect();
+            let cycle = loop {
+                let next = match self.waiting_on(path[path.len() - 1], &reqs, &movers, &candidates, &status) {
+                    Some(next) => next,
+                    None       => break None,
+                };
+                if seen.contains(&next) {
+                    let idx = path.iter().position(|p| *p == next).unwrap();
+                    break Some(path.split_off(idx));
+                }
+                seen.insert(next);
+                path.push(next);
+            };
+            let cycle = match cycle {
+                Some(cycle) => cycle,
+                None => {
+                    // settling always leaves something to wait on, but a stuck move is better denied than looped on
+                    let stuck = path[path.len() - 1];
+                    status[stuck]  = Status::Denied;
+                    denials[stuck] = Some(MoveDenial::Blocked);
+                    continue;
+                },
+            };
+            // allies can swap or rotate places, but anyone else just bumps into each other, as does anyone waiting on
+            // a claim, since the cells aren't really being traded
+            let is_claim: Vec<bool> = cycle.iter().map(|i| !claimants(&candidates[&reqs[*i].target()], &reqs, &status).contains(i)).collect();
+            let is_allowed = cycle.iter().zip(is_claim.iter()).all(|(i, is_claim)| !is_claim && reqs[i.clone()].faction == Faction::Ally);
+            for (n, i) in cycle.iter().enumerate() {
+                if is_allowed {
+                    status[i.clone()] = Status::Approved;
+                } else {
+                    let other = reqs[cycle[(n + 1) % cycle.len()]].entity;
+                    status[i.clone()]  = Status::Denied;
+                    denials[i.clone()] = Some(if is_claim[n] { MoveDenial::Contested(other) } else { MoveDenial::Occupied(other) });
+                }
+            }
+        }
+
+        let mut resolution = MoveResolution::default();
+        for (i, req) in reqs.iter().enumerate() {
+            match denials[i] {
+                Some(denial) => resolution.denied.push((req.clone(), denial)),
+                None         => {
+                    self.remove_occupant(&req.from, req.entity);
+                    resolution.approved.push((req.entity, req.target()));
+                },
+            }
+        }
+        for (i, req) in reqs.iter().enumerate() {
+            if status[i] == Status::Approved {
+                self.add_occupant(&req.target(), req.entity, req.movement);
+            }
+        }
+        resolution
+    }
+
+    /// The pending request that a pending request is waiting on: a higher priority claim on the same cell, or whoever is
+    /// still in that cell
+    fn waiting_on(&self, i: usize, reqs: &[MoveReq], movers: &HashMap<Entity, usize>, candidates: &HashMap<Pos, Vec<usize>>, status: &[Status]) -> Option<usize> {
+        let req = &reqs[i];
+        let claimants = claimants(&candidates[&req.target()], reqs, status);
+        if claimants.contains(&i) {
+            self.occupants(&req.target()).iter()
+                .filter(|o| o.entity != req.entity)
+                .filter_map(|o| movers.get(&o.entity).cloned())
+                .find(|j| status[j.clone()] == Status::Pending)
+        } else {
+            claimants.into_iter().find(|j| status[j.clone()] == Status::Pending)
+        }
+    }
+}
+
+/// Whoever currently has the claim on a cell: the highest priority request that hasn't been denied, along with any others
+/// if they're all small enough to share
+fn claimants(candidates: &[usize], reqs: &[MoveReq], status: &[Status]) -> Vec<usize> {
+    let is_small = |i: &usize| reqs[i.clone()].movement.contains(Movement::Small);
+    let mut remaining = candidates.iter().cloned().filter(|i| status[i.clone()] != Status::Denied);
+    match remaining.next() {
+        Some(first) if is_small(&first) => std::iter::once(first).chain(remaining.filter(is_small)).collect(),
+        Some(first)                     => vec![first],
+        None                            => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(id: u32, from: Pos, dir: Dir, faction: Faction, priority: i32) -> MoveReq {
+        MoveReq { entity: Entity::new(id), from, dir, movement: EnumSet::empty(), faction, priority: MovePriority(priority) }
+    }
+
+    fn pos(x: i32, y: i32) -> Pos {
+        Pos { x, y, z: 0 }
+    }
+
+    fn grid_with(reqs: &[MoveReq], others: &[(u32, Pos)]) -> Grid {
+        let mut grid = Grid::new(8, 8, 1);
+        for req in reqs {
+            grid.add_occupant(&req.from, req.entity, req.movement);
+        }
+        for (id, pos) in others {
+            grid.add_occupant(pos, Entity::new(*id), EnumSet::empty());
+        }
+        grid
+    }
+
+    fn denial(resolution: &MoveResolution, id: u32) -> Option<MoveDenial> {
+        resolution.denied.iter().find(|(req, _)| req.entity.id() == id).map(|(_, denial)| *denial)
+    }
+
+    fn approved_to(resolution: &MoveResolution, id: u32) -> Option<Pos> {
+        resolution.approved.iter().find(|(entity, _)| entity.id() == id).map(|(_, pos)| *pos)
+    }
+
+    #[test]
+    fn chains_follow_each_other() {
+        let reqs = vec![req(1, pos(1, 0), Dir::East, Faction::Neutral, 0), req(2, pos(2, 0), Dir::East, Faction::Neutral, 0)];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(approved_to(&resolution, 1), Some(pos(2, 0)));
+        assert_eq!(approved_to(&resolution, 2), Some(pos(3, 0)));
+        assert_eq!(grid.occupants(&pos(1, 0)).len(), 0);
+    }
+
+    #[test]
+    fn chains_stop_behind_a_blocker() {
+        let reqs = vec![req(1, pos(1, 0), Dir::East, Faction::Neutral, 0), req(2, pos(2, 0), Dir::East, Faction::Neutral, 0)];
+        let mut grid = grid_with(&reqs, &[(3, pos(3, 0))]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(denial(&resolution, 2), Some(MoveDenial::Occupied(Entity::new(3))));
+        assert_eq!(denial(&resolution, 1), Some(MoveDenial::Occupied(Entity::new(2))));
+    }
+
+    #[test]
+    fn allies_swap() {
+        let reqs = vec![req(1, pos(1, 0), Dir::East, Faction::Ally, 0), req(2, pos(2, 0), Dir::West, Faction::Ally, 0)];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(approved_to(&resolution, 1), Some(pos(2, 0)));
+        assert_eq!(approved_to(&resolution, 2), Some(pos(1, 0)));
+    }
+
+    #[test]
+    fn others_bump_instead_of_swapping() {
+        let reqs = vec![req(1, pos(1, 0), Dir::East, Faction::Ally, 0), req(2, pos(2, 0), Dir::West, Faction::Hostile, 0)];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(denial(&resolution, 1), Some(MoveDenial::Occupied(Entity::new(2))));
+        assert_eq!(denial(&resolution, 2), Some(MoveDenial::Occupied(Entity::new(1))));
+    }
+
+    #[test]
+    fn allies_rotate() {
+        let reqs = vec![
+            req(1, pos(1, 1), Dir::East,  Faction::Ally, 0),
+            req(2, pos(2, 1), Dir::South, Faction::Ally, 0),
+            req(3, pos(2, 2), Dir::West,  Faction::Ally, 0),
+            req(4, pos(1, 2), Dir::North, Faction::Ally, 0),
+        ];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(resolution.approved.len(), 4);
+        assert_eq!(approved_to(&resolution, 4), Some(pos(1, 1)));
+    }
+
+    #[test]
+    fn priority_wins_contested_cells() {
+        let reqs = vec![req(1, pos(1, 0), Dir::East, Faction::Neutral, 0), req(2, pos(3, 0), Dir::West, Faction::Neutral, 5)];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(approved_to(&resolution, 2), Some(pos(2, 0)));
+        assert_eq!(denial(&resolution, 1), Some(MoveDenial::Contested(Entity::new(2))));
+    }
+
+    #[test]
+    fn priority_ties_go_to_the_oldest() {
+        let reqs = vec![req(2, pos(1, 0), Dir::East, Faction::Neutral, 0), req(1, pos(3, 0), Dir::West, Faction::Neutral, 0)];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs.clone());
+        assert_eq!(approved_to(&resolution, 1), Some(pos(2, 0)));
+        assert_eq!(denial(&resolution, 2), Some(MoveDenial::Contested(Entity::new(1))));
+
+        // the order requests come in doesn't matter
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs.into_iter().rev().collect());
+        assert_eq!(approved_to(&resolution, 1), Some(pos(2, 0)));
+    }
+
+    #[test]
+    fn losers_get_the_cell_when_the_winner_is_denied() {
+        // 2 has priority for (2, 0), but can't share it with the small entity already there, while 1 can
+        let reqs = vec![
+            MoveReq { movement: Movement::Small.into(), ..req(1, pos(1, 0), Dir::East, Faction::Neutral, 0) },
+            req(2, pos(2, 1), Dir::North, Faction::Neutral, 5),
+        ];
+        let mut grid = grid_with(&reqs, &[]);
+        grid.add_occupant(&pos(2, 0), Entity::new(3), Movement::Small.into());
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(denial(&resolution, 2), Some(MoveDenial::Occupied(Entity::new(3))));
+        assert_eq!(approved_to(&resolution, 1), Some(pos(2, 0)));
+    }
+
+    #[test]
+    fn cycles_through_a_claim_are_denied() {
+        // 2 waits for 3 to leave (2, 0), 3 waits for 1 to leave (1, 0), and 1 waits to see if 2 gets (2, 0)
+        let reqs = vec![
+            req(1, pos(1, 0), Dir::East,  Faction::Ally, 0),
+            req(2, pos(2, 1), Dir::North, Faction::Ally, 5),
+            req(3, pos(2, 0), Dir::West,  Faction::Ally, 0),
+        ];
+        let mut grid = grid_with(&reqs, &[]);
+        let resolution = grid.resolve_moves(reqs);
+        assert_eq!(denial(&resolution, 1), Some(MoveDenial::Contested(Entity::new(2))));
+        assert_eq!(denial(&resolution, 2), Some(MoveDenial::Occupied(Entity::new(3))));
+        assert_eq!(denial(&resolution, 3), Some(MoveDenial::Occupied(Entity::new(1))));
+        assert_eq!(grid.occupants(&pos(2, 0)).len(), 1);
+    }
+}
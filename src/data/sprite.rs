@@ -166,24 +166,56 @@ impl AnimState {
         if self.timer.finished() {
             match &info.anim {
                 AnimInfo::Animated {anim_vec, ..} => {
-                    self.cur_frame_idx = (self.cur_frame_idx + 1) % anim_vec[self.cur_anim_idx].frames.len();
+                    let frame_count = anim_vec[self.cur_anim_idx].frames.len();
+                    if frame_count > 0 {
+                        self.cur_frame_idx = (self.cur_frame_idx + 1) % frame_count;
+                    }
                 },
                 _ => (),
             }
         }
     }
 
+    /// Switches to another animation from its first frame
+    pub fn set_anim(&mut self, anim_idx: usize, info: &SpriteInfo) {
+        if let AnimInfo::Animated {anim_vec, ..} = &info.anim {
+            if let Some(anim) = anim_vec.get(anim_idx) {
+                self.cur_anim_idx  = anim_idx;
+                self.cur_frame_idx = 0;
+                self.timer         = Timer::new(anim.delay, true);
+            }
+        }
+    }
+
+    /// Jumps to a frame of the current animation, wrapping around if it's past the end. Does nothing if the animation has
+    /// no frames
+    pub fn set_frame(&mut self, frame_idx: usize, info: &SpriteInfo) {
+        if let AnimInfo::Animated {anim_vec, ..} = &info.anim {
+            let frame_count = anim_vec[self.cur_anim_idx].frames.len();
+            if frame_count > 0 {
+                self.cur_frame_idx = frame_idx % frame_count;
+                self.timer.reset();
+            }
+        }
+    }
+
     pub fn cur_tint(&self, info: &SpriteInfo) -> Palette {
         match &info.anim {
-            AnimInfo::Animated {anim_vec, ..} => anim_vec[self.cur_anim_idx].frames[self.cur_frame_idx].tint.clone(),
+            AnimInfo::Animated {anim_vec, ..} => anim_vec[self.cur_anim_idx].frames.get(self.cur_frame_idx)
+                .map_or_else(|| info.anim.default_tint(), |frame| frame.tint.clone()),
             _ => info.anim.default_tint(),
         }
     }
 
     pub fn cur_index(&self, info: &SpriteInfo) -> u32 {
         match &info.anim {
-            AnimInfo::Animated {anim_vec, ..} => anim_vec[self.cur_anim_idx].frames[self.cur_frame_idx].index.clone(),
+            AnimInfo::Animated {anim_vec, ..} => anim_vec[self.cur_anim_idx].frames.get(self.cur_frame_idx)
+                .map_or_else(|| info.anim.default_index(), |frame| frame.index.clone()),
             _ => info.anim.default_index(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// A tint set by a script, used instead of the tint of each animation frame
+#[derive(Clone, Copy, Debug)]
+pub struct TintOverride(pub Palette);
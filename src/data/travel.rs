@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use enumset::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use crate::data::action::*;
+use crate::data::level::*;
+
+const MAX_PATH_NODES: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub enum Route {
+    Run(Dir),
+    Path(VecDeque<Pos>),
+}
+
+/// Automatic movement over several turns, stopping when something new shows up
+#[derive(Clone, Debug)]
+pub struct Travel {
+    pub route:   Route,
+    pub seen:    Option<Vec<Entity>>, // entities already in view when travel began
+    pub started: f64,
+}
+
+impl Travel {
+    pub fn run(dir: Dir, started: f64) -> Travel {
+        Travel { route: Route::Run(dir), seen: None, started }
+    }
+
+    pub fn path(path: VecDeque<Pos>, started: f64) -> Travel {
+        Travel { route: Route::Path(path), seen: None, started }
+    }
+
+    pub fn is_run(&self) -> bool {
+        match self.route {
+            Route::Run(_)  => true,
+            Route::Path(_) => false,
+        }
+    }
+
+    pub fn next_dir(&self, pos: &Pos) -> Option<Dir> {
+        match &self.route {
+            Route::Run(dir)   => Some(dir.clone()),
+            Route::Path(path) => path.front().and_then(|next| pos.dir_to(next)),
+        }
+    }
+
+    /// Records that a step was taken, returning false once the route is finished
+    pub fn advance(&mut self) -> bool {
+        match &mut self.route {
+            Route::Run(_)     => true,
+            Route::Path(path) => {
+                path.pop_front();
+                !path.is_empty()
+            },
+        }
+    }
+}
+
+impl Grid {
+    /// Every entity that can be seen from `from`
+    pub fn visible_occupants(&self, from: &Pos) -> Vec<Entity> {
+        self.occupied_cells()
+            .filter(|(pos, _)| self.can_see(from, pos))
+            .flat_map(|(_, occupants)| occupants.iter().map(|o| o.entity))
+            .collect()
+    }
+
+    /// If the walls on either side of a run change after stepping from `prev` to `next`, such as a corridor opening into a room or a side passage
+    pub fn is_branching(&self, prev: &Pos, next: &Pos, dir: Dir, movement: EnumSet<Movement>) -> bool {
+        let sides = |pos: &Pos| (self.can_enter(&pos.step(dir.rotate(-2)), movement), self.can_enter(&pos.step(dir.rotate(2)), movement));
+        sides(prev) != sides(next)
+    }
+
+    /// Shortest route from `from` to `to` for an entity with the given movement, excluding `from` itself
+    pub fn find_path(&self, from: &Pos, to: &Pos, movement: EnumSet<Movement>) -> Option<VecDeque<Pos>> {
+        if from.z != to.z || !self.can_enter(to, movement) {
+            return None;
+        }
+        let mut open      = BinaryHeap::new();
+        let mut came_from = HashMap::new();
+        let mut cost      = HashMap::new();
+        let mut counter   = 0; // breaks ties in insertion order so paths are deterministic
+        open.push(Reverse((from.distance(to), counter, from.clone())));
+        cost.insert(from.clone(), 0);
+
+        while let Some(Reverse((_, _, cur))) = open.pop() {
+            if cur == *to {
+                let mut path = VecDeque::new();
+                let mut step = cur;
+                while step != *from {
+                    path.push_front(step);
+                    step = came_from[&step];
+                }
+                return Some(path);
+            }
+            if came_from.len() > MAX_PATH_NODES {
+                return None;
+            }
+            let next_cost = cost[&cur] + 1;
+            for dir in Dir::ALL.iter() {
+                let next = cur.step(dir.clone());
+                if self.can_enter(&next, movement) && cost.get(&next).map_or(true, |c| next_cost < *c) {
+                    counter += 1;
+                    cost.insert(next, next_cost);
+                    came_from.insert(next, cur);
+                    open.push(Reverse((next_cost + next.distance(to), counter, next)));
+                }
+            }
+        }
+        None
+    }
+}
@@ -1,3 +1,194 @@
+use bevy::{
+    ecs::schedule::ShouldRun,
+    prelude::*,
+};
+
+use crate::data::action::*;
+
+pub const ACTION_COST: i32 = 100;
+pub const MAX_TICKS_PER_TURN: usize = 100;
 
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
-pub struct TurnCount(pub usize);
\ No newline at end of file
+pub struct TurnCount(pub usize);
+
+/// The parts of a turn, in order. Each one is also the label of the stage that runs it
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, StageLabel)]
+pub enum TurnPhase {
+    Start,
+    PlayerAction,
+    NpcActions,
+    Environment,
+    End,
+}
+
+impl Default for TurnPhase {
+    fn default() -> TurnPhase {
+        TurnPhase::Start
+    }
+}
+
+impl TurnPhase {
+    pub fn next(&self) -> TurnPhase {
+        match self {
+            TurnPhase::Start        => TurnPhase::PlayerAction,
+            TurnPhase::PlayerAction => TurnPhase::NpcActions,
+            TurnPhase::NpcActions   => TurnPhase::Environment,
+            TurnPhase::Environment  => TurnPhase::End,
+            TurnPhase::End          => TurnPhase::Start,
+        }
+    }
+
+    pub fn should_run(&self, phase: TurnPhase) -> ShouldRun {
+        if *self == phase { ShouldRun::Yes } else { ShouldRun::No }
+    }
+}
+
+/// Whether the world waits for the player to act, or keeps going without them
+#[derive(Clone, Debug)]
+pub enum TurnMode {
+    TurnBased,
+    RealTime { timer: Timer, is_paused: bool }, // if the timer runs out before the player acts, they wait a turn
+}
+
+impl Default for TurnMode {
+    fn default() -> TurnMode {
+        TurnMode::TurnBased
+    }
+}
+
+impl TurnMode {
+    pub fn real_time(seconds_per_turn: f32) -> TurnMode {
+        TurnMode::RealTime { timer: Timer::from_seconds(seconds_per_turn, false), is_paused: false }
+    }
+}
+
+/// Actors gain `speed` energy every tick, and can act once they have at least `ACTION_COST` of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Energy {
+    pub speed:  i32,
+    pub energy: i32,
+}
+
+impl Energy {
+    pub fn new(speed: i32) -> Energy {
+        Energy { speed, energy: ACTION_COST }
+    }
+
+    pub fn can_act(&self) -> bool {
+        self.energy >= ACTION_COST
+    }
+
+    /// Costs above `ACTION_COST` take several ticks to recover from, which is how multi-turn actions work
+    pub fn spend(&mut self, cost: i32) {
+        self.energy -= cost.max(1);
+    }
+
+    pub fn tick(&mut self) {
+        self.energy += self.speed;
+    }
+}
+
+/// An entity with `Energy`, as seen by `take_npc_turns`
+#[derive(Clone, Copy, Debug)]
+pub struct Actor {
+    pub entity:        Entity,
+    pub energy:        Energy,
+    pub is_controlled: bool, // acts through `LocalActions` rather than being run here
+}
+
+/// NPCs act in initiative order until a controlled actor has the energy to act again. Anyone who becomes ready on the same
+/// tick as a controlled actor goes after it, so at equal speeds everyone acts once per turn. `act` is called for each NPC
+/// turn and returns the energy it spent
+pub fn take_npc_turns<F: FnMut(Entity) -> i32>(actors: &mut [Actor], mut act: F) {
+    let has_controlled = actors.iter().any(|a| a.is_controlled);
+    for _ in 0..MAX_TICKS_PER_TURN {
+        while let Some(idx) = next_npc(actors) {
+            let cost = act(actors[idx].entity);
+            actors[idx].energy.spend(cost);
+        }
+        if !has_controlled || is_controlled_ready(actors) {
+            return;
+        }
+        actors.iter_mut().for_each(|a| a.energy.tick());
+        if is_controlled_ready(actors) {
+            return;
+        }
+    }
+}
+
+/// The NPC with the most energy goes first, with ties going to whichever entity is oldest
+fn next_npc(actors: &[Actor]) -> Option<usize> {
+    actors.iter().enumerate()
+        .filter(|(_, a)| !a.is_controlled && a.energy.can_act())
+        .max_by_key(|(_, a)| (a.energy.energy, std::cmp::Reverse(a.entity.id())))
+        .map(|(idx, _)| idx)
+}
+
+fn is_controlled_ready(actors: &[Actor]) -> bool {
+    actors.iter().any(|a| a.is_controlled && a.energy.can_act())
+}
+
+/// Sent when an entity controlled through `LocalActions` uses its turn
+#[derive(Clone, Copy, Debug)]
+pub struct ActionTaken {
+    pub entity: Entity,
+    pub action: Action,
+    pub cost:   i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor(id: u32, speed: i32, is_controlled: bool) -> Actor {
+        Actor { entity: Entity::new(id), energy: Energy::new(speed), is_controlled }
+    }
+
+    /// Plays `turns` turns, with the controlled actor at index 0 acting first each time, returning how often each NPC acted
+    fn play(actors: &mut [Actor], turns: usize) -> Vec<usize> {
+        let mut counts = vec![0; actors.len()];
+        for _ in 0..turns {
+            assert!(actors[0].energy.can_act());
+            actors[0].energy.spend(ACTION_COST);
+            let ids: Vec<Entity> = actors.iter().map(|a| a.entity).collect();
+            take_npc_turns(actors, |entity| {
+                counts[ids.iter().position(|e| *e == entity).unwrap()] += 1;
+                ACTION_COST
+            });
+        }
+        counts
+    }
+
+    #[test]
+    fn equal_speeds_act_once_per_turn() {
+        let mut actors = [actor(0, 100, true), actor(1, 100, false), actor(2, 100, false)];
+        assert_eq!(play(&mut actors, 10), vec![0, 10, 10]);
+    }
+
+    #[test]
+    fn speed_sets_the_pace() {
+        let mut actors = [actor(0, 100, true), actor(1, 50, false), actor(2, 200, false)];
+        let counts = play(&mut actors, 10);
+        assert_eq!(counts[1], 5);
+        assert_eq!(counts[2], 19); // one action on the first turn, from the energy everyone starts with
+    }
+
+    #[test]
+    fn fast_player_acts_twice_per_npc_turn() {
+        let mut actors = [actor(0, 200, true), actor(1, 100, false)];
+        // both start out ready, then the NPC acts once every two player turns
+        assert_eq!(play(&mut actors, 10), vec![0, 6]);
+    }
+
+    #[test]
+    fn ties_go_to_the_oldest() {
+        let mut actors = [actor(0, 100, true), actor(2, 100, false), actor(1, 100, false)];
+        let mut order = Vec::new();
+        actors[0].energy.spend(ACTION_COST);
+        take_npc_turns(&mut actors, |entity| {
+            order.push(entity.id());
+            ACTION_COST
+        });
+        assert_eq!(order, vec![1, 2]);
+    }
+}
@@ -0,0 +1,94 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+use crate::data::action::*;
+use crate::data::level::*;
+use crate::data::sprite::TILE_SIZE;
+
+pub const HOP_HEIGHT: f32 = 0.15;    // in tiles
+pub const BUMP_DISTANCE: f32 = 0.25; // in tiles
+pub const SECONDS_TO_BUMP: f32 = 0.5 * SECONDS_TO_WALK;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    SineInOut,
+}
+
+impl Easing {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.max(0.).min(1.);
+        match self {
+            Easing::Linear    => t,
+            Easing::QuadIn    => t * t,
+            Easing::QuadOut   => t * (2. - t),
+            Easing::QuadInOut => if t < 0.5 { 2. * t * t } else { -1. + (4. - 2. * t) * t },
+            Easing::SineInOut => 0.5 * (1. - (PI * t).cos()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    Slide,
+    Hop { height: f32 },
+    Bump,
+}
+
+/// Visual interpolation between two tiles; the logical `Pos` is already at its destination while this plays
+#[derive(Clone, Debug)]
+pub struct Tween {
+    pub from:   Vec3,
+    pub to:     Vec3,
+    pub timer:  Timer,
+    pub easing: Easing,
+    pub motion: Motion,
+}
+
+impl Tween {
+    pub fn hop(from: Vec3, to: Vec3, seconds: f32, map_scale: &MapScale) -> Tween {
+        Tween {
+            from,
+            to,
+            timer:  Timer::from_seconds(seconds, false),
+            easing: Easing::QuadOut,
+            motion: Motion::Hop { height: HOP_HEIGHT * TILE_SIZE * map_scale.0 },
+        }
+    }
+
+    pub fn bump(from: Vec3, dir: Dir, map_scale: &MapScale) -> Tween {
+        let step   = Pos::default().step(dir);
+        let offset = Vec3::new(step.x as f32, -step.y as f32, 0.) * BUMP_DISTANCE * TILE_SIZE * map_scale.0;
+        Tween {
+            from,
+            to:     from + offset,
+            timer:  Timer::from_seconds(SECONDS_TO_BUMP, false),
+            easing: Easing::SineInOut,
+            motion: Motion::Bump,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.timer.finished()
+    }
+
+    /// Position along the path between tiles, without any hop or bump offsets
+    pub fn ground_translation(&self) -> Vec3 {
+        match self.motion {
+            Motion::Bump => self.from,
+            _            => self.from.lerp(self.to, self.easing.apply(self.timer.percent())),
+        }
+    }
+
+    pub fn translation(&self) -> Vec3 {
+        let t = self.timer.percent();
+        match self.motion {
+            Motion::Slide          => self.ground_translation(),
+            Motion::Hop { height } => self.ground_translation() + Vec3::new(0., height * (PI * t).sin(), 0.),
+            Motion::Bump           => self.from.lerp(self.to, self.easing.apply((PI * t).sin())),
+        }
+    }
+}
@@ -2,21 +2,66 @@ use bevy::prelude::*;
 use enumset::*;
 use rlua::prelude::*;
 
+use crate::data::action::*;
+use crate::data::color::*;
+use crate::data::level::*;
+use crate::lua::global::*;
+use crate::lua::message::*;
 use crate::lua::types::*;
 use crate::lua::util::*;
+use crate::lua::value::*;
+use crate::lua::world::*;
 
 #[derive(Debug, EnumSetType)]
 pub enum EntityEvent {
     OnInit,
     OnUpdate,
+    OnTurnStart,
+    OnTurnEnd,
+    OnMoveDenied,
+    OnDestroy,
+    OnMessage,
 }
 
 impl EntityEvent {
     pub fn from_string(str: &str) -> Result<EntityEvent, &str> {
         match str {
-            "on_init"   => Ok(EntityEvent::OnInit),
-            "on_update" => Ok(EntityEvent::OnUpdate),
-            s           => Err(s),
+            "on_init"        => Ok(EntityEvent::OnInit),
+            "on_update"      => Ok(EntityEvent::OnUpdate),
+            "on_turn_start"  => Ok(EntityEvent::OnTurnStart),
+            "on_turn_end"    => Ok(EntityEvent::OnTurnEnd),
+            "on_move_denied" => Ok(EntityEvent::OnMoveDenied),
+            "on_destroy"     => Ok(EntityEvent::OnDestroy),
+            "on_message"     => Ok(EntityEvent::OnMessage),
+            s                => Err(s),
+        }
+    }
+}
+
+/// Something passed to the handlers of an entity event
+#[derive(Clone, Debug)]
+pub enum EventArg {
+    Value(StoredValue),
+    Entity(Entity), // passed as a `LuaEntity`
+}
+
+impl From<StoredValue> for EventArg {
+    fn from(value: StoredValue) -> EventArg {
+        EventArg::Value(value)
+    }
+}
+
+impl From<Entity> for EventArg {
+    fn from(entity: Entity) -> EventArg {
+        EventArg::Entity(entity)
+    }
+}
+
+impl<'lua> ToLua<'lua> for EventArg {
+    fn to_lua(self, lua_ctx: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        match self {
+            EventArg::Value(value)   => value.to_lua(lua_ctx),
+            EventArg::Entity(entity) => LuaEntity::new(entity).to_lua(lua_ctx),
         }
     }
 }
@@ -25,20 +70,100 @@ impl EntityEvent {
 pub struct LuaEntity {
     pub entity: Entity,
     pub events_registered: EnumSet<EntityEvent>,
+    pub action_cost: Option<i32>, // energy spent by this turn's action, if not the usual amount
+    pub event_args: Vec<EventArg>, // passed to the handlers of the event being run
+    pub is_script_run: bool, // handlers registered are the script's own, replaced when it's reloaded
 }
 
 impl LuaEntity {
-    pub const LUA_ENTITY_NAME: &'static str        = "local_entity";
-    pub const ENTITY_EVENTS_VAR_NAME: &'static str = "_E_EVT";
-    pub const ENTITY_EVENT_COUNTER: &'static str   = "_E_CTR";
+    pub const LUA_ENTITY_NAME: &'static str          = "local_entity";
+    pub const ENTITY_EVENTS_VAR_NAME: &'static str   = "_E_EVT";
+    pub const ENTITY_EVENT_COUNTER: &'static str     = "_E_CTR";
+    pub const ENTITY_DATA_VAR_NAME: &'static str     = "_E_DAT";
+    pub const SCRIPT_HANDLERS_VAR_NAME: &'static str = "_E_SCR"; // ids of the handlers each entity's script registered
 
     pub fn new(entity: Entity) -> LuaEntity {
         LuaEntity {
             entity,
             events_registered: EnumSet::default(),
+            action_cost: None,
+            event_args: Vec::new(),
+            is_script_run: false,
         }
     }
 
+    /// The entity as its script runs, before any of its events
+    pub fn for_script(entity: Entity) -> LuaEntity {
+        LuaEntity { is_script_run: true, ..LuaEntity::new(entity) }
+    }
+
+    pub fn with_args(entity: Entity, event_args: Vec<EventArg>) -> LuaEntity {
+        LuaEntity { event_args, ..LuaEntity::new(entity) }
+    }
+
+    /// Key for the entity's tables in Lua. Includes the generation, so a reused id never picks up the handlers or data
+    /// of the entity that had it before
+    pub fn key(entity: Entity) -> i64 {
+        entity.to_bits() as i64
+    }
+
+    /// Drops every handler the entity registered, so nothing runs for it once it's gone
+    pub fn clear_handlers(lua_ctx: LuaContext, entity: Entity) -> LuaResult<()> {
+        let entities: Option<LuaTable> = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME)?;
+        if let Some(entities) = entities {
+            entities.set(LuaEntity::key(entity), LuaValue::Nil)?;
+        }
+        let script_handlers: Option<LuaTable> = get_if_present(&lua_ctx.globals(), LuaEntity::SCRIPT_HANDLERS_VAR_NAME)?;
+        if let Some(script_handlers) = script_handlers {
+            script_handlers.set(LuaEntity::key(entity), LuaValue::Nil)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the handlers the entity's script registered as it ran, keeping any its handlers registered afterwards
+    pub fn clear_script_handlers(lua_ctx: LuaContext, entity: Entity) -> LuaResult<()> {
+        let key = LuaEntity::key(entity);
+        let script_handlers: LuaTable = compute_if_absent(&lua_ctx.globals(), LuaEntity::SCRIPT_HANDLERS_VAR_NAME, || lua_ctx.create_table())?;
+        let ids = match get_if_present::<_, LuaTable>(&script_handlers, key)? {
+            Some(ids) => ids,
+            None      => return Ok(()),
+        };
+        if let Some(events) = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME)?
+            .and_then::<LuaTable, _>(|t: LuaTable| get_if_present(&t, key).unwrap()) {
+            for pair in events.pairs::<u8, LuaTable>() {
+                let (_, handlers) = pair?;
+                for id in ids.clone().pairs::<i32, bool>() {
+                    handlers.set(id?.0, LuaValue::Nil)?;
+                }
+            }
+        }
+        script_handlers.set(key, LuaValue::Nil)
+    }
+
+    /// The events the entity has handlers left for
+    pub fn registered_events(lua_ctx: LuaContext, entity: Entity) -> LuaResult<EnumSet<EntityEvent>> {
+        let mut registered = EnumSet::empty();
+        if let Some(events) = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME)?
+            .and_then::<LuaTable, _>(|t: LuaTable| get_if_present(&t, LuaEntity::key(entity)).unwrap()) {
+            for event in EnumSet::<EntityEvent>::all() {
+                let handlers: Option<LuaTable> = get_if_present(&events, event as u8)?;
+                if handlers.map_or(false, |h| h.pairs::<LuaValue, LuaValue>().next().is_some()) {
+                    registered |= event;
+                }
+            }
+        }
+        Ok(registered)
+    }
+
+    /// Drops the table from `local_entity:data()`, which otherwise outlives reloading the entity's script
+    pub fn clear_data(lua_ctx: LuaContext, entity: Entity) -> LuaResult<()> {
+        let data: Option<LuaTable> = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_DATA_VAR_NAME)?;
+        if let Some(data) = data {
+            data.set(LuaEntity::key(entity), LuaValue::Nil)?;
+        }
+        Ok(())
+    }
+
     pub fn update_entity(&self, commands: &mut Commands) {
         commands.entity(self.entity)
             .insert(self.events_registered);
@@ -71,15 +196,10 @@ impl LuaInstance for LuaEntity {
 impl LuaEvent<EntityEvent> for LuaEntity {
     fn run_handlers(&self, lua_ctx: LuaContext, event: EntityEvent) -> LuaResult<()> {
         if let Some(handlers) = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME)?
-            .and_then(|t: LuaTable| get_if_present(&t, self.entity.id()).unwrap())
+            .and_then(|t: LuaTable| get_if_present(&t, LuaEntity::key(self.entity)).unwrap())
             .and_then::<LuaTable, _>(|t: LuaTable| get_if_present(&t, event as u8).unwrap()) {
-            for pair in handlers.pairs::<i32, LuaFunction>() {
-                if let Ok((_, f)) = pair {
-                    f.call::<_, ()>(())?;
-                } else {
-                    println!("Error in LuaEntity event handler for {:?}: {:?}", event, pair);
-                }
-            }
+            let args = self.event_args.iter().cloned().map(|a| a.to_lua(lua_ctx)).collect::<LuaResult<Vec<_>>>()?;
+            return call_handlers(lua_ctx, &handlers, args);
         }
         Ok(())
     }
@@ -90,6 +210,73 @@ impl LuaUserData for LuaEntity {
         methods.add_method("id", |_, this, ()| {
             Ok(this.entity.id())
         });
+        methods.add_method("pos", |lua_ctx, this, ()| {
+            let pos = Global::world(lua_ctx)?.lock().pos(this.entity);
+            Ok((pos.map(|p| p.x), pos.map(|p| p.y), pos.map(|p| p.z)))
+        });
+        methods.add_method("can_move", |lua_ctx, this, dir: String| {
+            let dir = parse_dir(&dir)?;
+            Ok(Global::world(lua_ctx)?.lock().can_move(this.entity, dir))
+        });
+        methods.add_method("move", |lua_ctx, this, dir: String| {
+            let dir = parse_dir(&dir)?;
+            Ok(Global::world(lua_ctx)?.lock().move_entity(this.entity, dir))
+        });
+        methods.add_method("teleport", |lua_ctx, this, (x, y, z): (i32, i32, i32)| {
+            Ok(Global::world(lua_ctx)?.lock().teleport(this.entity, Pos { x, y, z }))
+        });
+        // done on the entity's next turn, once its on_update has run
+        methods.add_method("act", |lua_ctx, this, name: String| {
+            let action = Action::from_name(&name).ok_or_else(|| LuaError::RuntimeError(format!("`{}` is not an action, expected \"wait\" or a direction", name)))?;
+            if Global::world(lua_ctx)?.lock().queue_action(this.entity, action) {
+                Ok(())
+            } else {
+                Err(LuaError::RuntimeError("Entity's actions don't come from scripts, its prefab needs `source: Some(Ai)` or `source: Some(Scripted)`".to_string()))
+            }
+        });
+        // on_destroy runs, and the entity's handlers and data are dropped, once it's actually despawned
+        methods.add_method("despawn", |lua_ctx, this, ()| {
+            Ok(Global::world(lua_ctx)?.lock().despawn(this.entity))
+        });
+        methods.add_method("data", |lua_ctx, this, ()| {
+            let data = compute_if_absent(&lua_ctx.globals(), LuaEntity::ENTITY_DATA_VAR_NAME, || lua_ctx.create_table())?;
+            compute_if_absent::<_, LuaTable, _>(&data, LuaEntity::key(this.entity), || lua_ctx.create_table())
+        });
+        // handled by the target's on_message(name, payload, sender), right away unless the delivery is "queued"
+        methods.add_method("send", |lua_ctx, this, (target, name, payload, delivery): (LuaEntity, String, StoredValue, Option<String>)| {
+            let message = Message { sender: Some(this.entity), target: Some(target.entity), name, payload };
+            send(lua_ctx, &Global::mailbox(lua_ctx)?, message, delivery)
+        });
+        // Sprite
+        methods.add_method("set_anim", |lua_ctx, this, name: String| {
+            if Global::world(lua_ctx)?.lock().set_anim(this.entity, &name) {
+                Ok(())
+            } else {
+                Err(LuaError::RuntimeError(format!("Entity has no animation named `{}`", name)))
+            }
+        });
+        methods.add_method("current_anim", |lua_ctx, this, ()| {
+            Ok(Global::world(lua_ctx)?.lock().current_anim(this.entity))
+        });
+        methods.add_method("set_tint", |lua_ctx, this, tint: LuaValue| {
+            let palette = parse_tint(tint)?;
+            Global::world(lua_ctx)?.lock().change_sprite(this.entity, SpriteChange::Tint(palette));
+            Ok(())
+        });
+        // numbered from 1 like everything else in Lua
+        methods.add_method("set_frame", |lua_ctx, this, frame: u32| {
+            let index = frame.checked_sub(1).ok_or_else(|| LuaError::RuntimeError("Frames are numbered from 1".to_string()))?;
+            Global::world(lua_ctx)?.lock().change_sprite(this.entity, SpriteChange::Frame(index));
+            Ok(())
+        });
+        methods.add_method("set_visible", |lua_ctx, this, is_visible: bool| {
+            Global::world(lua_ctx)?.lock().change_sprite(this.entity, SpriteChange::Visible(is_visible));
+            Ok(())
+        });
+        methods.add_method_mut("set_action_cost", |_, this, cost: i32| {
+            this.action_cost = Some(cost);
+            Ok(())
+        });
         methods.add_method_mut("register", |lua_ctx, this, table: LuaTable| {
             let new_id = LuaEntity::next_id(lua_ctx)?;
             for pair in table.pairs::<String, LuaFunction>() {
@@ -98,14 +285,45 @@ impl LuaUserData for LuaEntity {
                     this.events_registered = this.events_registered | event;
 
                     let entities = compute_if_absent(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME, || lua_ctx.create_table())?;
-                    let events   = compute_if_absent(&entities, this.entity.id(), || lua_ctx.create_table())?;
+                    let events   = compute_if_absent(&entities, LuaEntity::key(this.entity), || lua_ctx.create_table())?;
                     let handlers = compute_if_absent(&events, event as u8, || lua_ctx.create_table())?;
                     handlers.set(new_id, f)?;
                 } else {
                     println!("failed to register event {:?}", pair);
                 }
             }
+            if this.is_script_run {
+                let script_handlers = compute_if_absent(&lua_ctx.globals(), LuaEntity::SCRIPT_HANDLERS_VAR_NAME, || lua_ctx.create_table())?;
+                let ids: LuaTable = compute_if_absent(&script_handlers, LuaEntity::key(this.entity), || lua_ctx.create_table())?;
+                ids.set(new_id, true)?;
+            }
             Ok(new_id)
         });
     }
-}
\ No newline at end of file
+}
+
+fn parse_dir(name: &str) -> LuaResult<Dir> {
+    Dir::from_name(name).ok_or_else(|| LuaError::RuntimeError(format!("`{}` is not a direction", name)))
+}
+
+/// A palette name such as `"Picasso"`, a CSS color such as `"#ff8800"`, or a table of 0-255 values like `{r = 255, g = 136, b = 0}`
+fn parse_tint(tint: LuaValue) -> LuaResult<Palette> {
+    match tint {
+        LuaValue::String(s) => {
+            let s = s.to_str()?;
+            if let Ok(palette) = ron::de::from_str::<Palette>(s) {
+                Ok(palette)
+            } else {
+                let c = s.parse::<css_color_parser::Color>().map_err(|_| LuaError::RuntimeError(format!("`{}` is not a palette name or color", s)))?;
+                Ok(Palette::DevCustom { r: c.r, g: c.g, b: c.b, a: (c.a * 255.) as u8 })
+            }
+        },
+        LuaValue::Table(t) => Ok(Palette::DevCustom {
+            r: t.get("r")?,
+            g: t.get("g")?,
+            b: t.get("b")?,
+            a: t.get::<_, Option<u8>>("a")?.unwrap_or(255),
+        }),
+        other => Err(LuaError::RuntimeError(format!("Expected a palette name, color or table for a tint, got {}", other.type_name()))),
+    }
+}
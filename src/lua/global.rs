@@ -1,52 +1,181 @@
+use bevy::prelude::Entity;
 use rlua::prelude::*;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}};
 
+use crate::data::level::*;
+use crate::data::random::*;
+use crate::lua::entity::*;
+use crate::lua::message::*;
+use crate::lua::types::*;
 use crate::lua::util::*;
+use crate::lua::value::*;
+use crate::lua::vars::*;
+use crate::lua::world::*;
 
 #[derive(Clone, Default)]
 pub struct Global {
-    pub counter: usize,
+    pub counter: Arc<AtomicUsize>, // shared with every synced copy, so ids are never handed out twice
     pub turn_count: usize,
     pub is_debug: bool,
-    pub var_watchers: HashMap<String, HashSet<usize>>,
+    pub vars: VarStore,
+    pub mailbox: Mailbox,
+    pub interrupted: Arc<AtomicBool>, // shared with every synced copy, so scripts can stop repeated actions
+    pub random: RandomStreams,
+    pub world: WorldView,
 }
 
 impl Global {
-    pub const GLOBAL_VAR_NAME: &'static str   = "global";
-    pub const VAR_VALUES_VAR_NAME: &'static str   = "_G_VAL";
+    pub const GLOBAL_VAR_NAME: &'static str       = "global";
     pub const VAR_HANDLERS_VAR_NAME: &'static str = "_G_HDL";
     pub const EVENTS_VAR_NAME: &'static str       = "_G_EVT";
+    pub const SNAPSHOT_VAR_NAME: &'static str     = "_G_SNP";
 
     pub fn init(lua_ctx: LuaContext) -> LuaResult<Global> {
-        let vars     = lua_ctx.create_table()?;
         let handlers = lua_ctx.create_table()?;
         let events   = lua_ctx.create_table()?;
+        let marked   = lua_ctx.create_table()?;
         let global = Global {is_debug: true, ..Global::default()};
-        lua_ctx.globals().set(Global::VAR_VALUES_VAR_NAME, vars)?;
         lua_ctx.globals().set(Global::VAR_HANDLERS_VAR_NAME, handlers)?;
         lua_ctx.globals().set(Global::EVENTS_VAR_NAME, events)?;
+        lua_ctx.globals().set(Global::SNAPSHOT_VAR_NAME, marked)?;
         lua_ctx.globals().set(Global::GLOBAL_VAR_NAME, global.clone())?;
+        // scripts share the run's seeded streams instead of Lua's own generator, so runs can be reproduced, and can only
+        // require what's been loaded from `assets/scripts`
+        lua_ctx.load(r#"
+            math.random = function(m, n) return global:random(m, n) end
+            math.randomseed = function() error("math.randomseed is disabled, the run's seed is used instead") end
+            -- modules come from the asset server through package.preload, never straight from the filesystem
+            package.path = ""
+            package.cpath = ""
+            package.searchers = { package.searchers[1] }
+        "#).exec()?;
         Ok(global)
     }
 
-    pub fn next_id(&mut self) -> usize {
-        self.counter += 1;
-        self.counter
+    /// The world view shared with the `global` in the given Lua state
+    pub fn world(lua_ctx: LuaContext) -> LuaResult<WorldView> {
+        let global: LuaAnyUserData = lua_ctx.globals().get(Global::GLOBAL_VAR_NAME)?;
+        let world = global.borrow::<Global>()?.world.clone();
+        Ok(world)
     }
+
+    /// The messages queued by scripts in the given Lua state
+    pub fn mailbox(lua_ctx: LuaContext) -> LuaResult<Mailbox> {
+        let global: LuaAnyUserData = lua_ctx.globals().get(Global::GLOBAL_VAR_NAME)?;
+        let mailbox = global.borrow::<Global>()?.mailbox.clone();
+        Ok(mailbox)
+    }
+
+    /// The level of the entity whose script is running, or the first level if there isn't one
+    fn local_level(lua_ctx: LuaContext, world: &WorldState) -> LuaResult<Entity> {
+        let local_entity: Option<LuaEntity> = lua_ctx.globals().get(LuaEntity::LUA_ENTITY_NAME)?;
+        world.level_of(local_entity.map(|e| e.entity)).ok_or_else(|| LuaError::RuntimeError("No level is loaded".to_string()))
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Copies out the variable store and every Lua global marked with `global:snapshot(name)`
+    pub fn take_snapshot(&self, lua_ctx: LuaContext) -> LuaResult<LuaSnapshot> {
+        let marked: LuaTable = lua_ctx.globals().get(Global::SNAPSHOT_VAR_NAME)?;
+        let mut snapshot = LuaSnapshot { vars: self.vars.to_saved(), ..LuaSnapshot::default() };
+        for pair in marked.pairs::<String, bool>() {
+            let (name, _) = pair?;
+            match lua_ctx.globals().get::<_, StoredValue>(name.clone()) {
+                Ok(val) => snapshot.marked.push((name, val)),
+                Err(e)  => println!("Can't snapshot {}: {}", name, e),
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Replaces the variable store and marked globals with a snapshot's, without notifying watchers
+    pub fn restore_snapshot(&self, lua_ctx: LuaContext, snapshot: &LuaSnapshot) -> LuaResult<()> {
+        self.vars.restore(snapshot.vars.clone());
+        for (name, val) in snapshot.marked.iter() {
+            lua_ctx.globals().set(name.clone(), val.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Runs every handler registered for an event with its payload, in the order they were registered
+    fn dispatch<'lua>(lua_ctx: LuaContext<'lua>, key: &str, payload: LuaValue<'lua>) -> LuaResult<()> {
+        if let Some(handlers) = get_if_present(&lua_ctx.globals(), Global::EVENTS_VAR_NAME)?
+            .and_then::<LuaTable, _>(|t: LuaTable| get_if_present(&t, event_name(key)).unwrap()) {
+            return call_handlers(lua_ctx, &handlers, vec![payload]);
+        }
+        Ok(())
+    }
+
+    /// Returns if a script asked to interrupt repeated actions since this was last called
+    pub fn take_interrupt(&self) -> bool {
+        self.interrupted.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// A global event, and the payload table passed to its handlers
+#[derive(Clone, Debug)]
+pub struct GlobalEvent<'a> {
+    pub key:      &'a str,
+    pub entities: Vec<(&'static str, Entity)>, // passed as `LuaEntity`s
+    pub values:   Vec<(&'static str, StoredValue)>,
+}
+
+impl<'a> GlobalEvent<'a> {
+    pub fn new(key: &'a str) -> GlobalEvent<'a> {
+        GlobalEvent { key, entities: Vec::new(), values: Vec::new() }
+    }
+
+    pub fn with_entity(mut self, name: &'static str, entity: Entity) -> GlobalEvent<'a> {
+        self.entities.push((name, entity));
+        self
+    }
+
+    pub fn with_value<V: Into<StoredValue>>(mut self, name: &'static str, value: V) -> GlobalEvent<'a> {
+        self.values.push((name, value.into()));
+        self
+    }
+}
+
+impl<'a> LuaEvent<GlobalEvent<'a>> for Global {
+    fn run_handlers(&self, lua_ctx: LuaContext, event: GlobalEvent<'a>) -> LuaResult<()> {
+        let payload = lua_ctx.create_table()?;
+        for (name, entity) in event.entities {
+            payload.set(name, LuaEntity::new(entity))?;
+        }
+        for (name, value) in event.values {
+            payload.set(name, value)?;
+        }
+        Global::dispatch(lua_ctx, event.key, LuaValue::Table(payload))
+    }
+}
+
+/// The stream a script asked for, or the one for scripts if it didn't say
+fn stream_name(stream: &Option<String>) -> LuaResult<&str> {
+    match stream.as_ref() {
+        Some(name) if STREAMS.contains(&name.as_str()) => Ok(name.as_str()),
+        Some(name) => Err(LuaError::RuntimeError(format!("`{}` is not a random stream, expected one of {}", name, STREAMS.join(", ")))),
+        None       => Ok(SCRIPT_STREAM),
+    }
+}
+
+/// Handlers can be registered as `turn_start` or `on_turn_start`, like entity events
+fn event_name(key: &str) -> &str {
+    key.strip_prefix("on_").unwrap_or(key)
 }
 
 impl LuaUserData for Global {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method_mut("next_id", |_, this, ()| {
+        methods.add_method("next_id", |_, this, ()| {
             Ok(this.next_id())
         });
-        methods.add_method_mut("register", |lua_ctx, this, table: LuaTable| {
+        methods.add_method("register", |lua_ctx, this, table: LuaTable| {
             let new_id = this.next_id();
             for pair in table.pairs::<String, LuaFunction>() {
                 if let Ok((event_key, f)) = pair {
                     let events   = compute_if_absent(&lua_ctx.globals(), Global::EVENTS_VAR_NAME, || lua_ctx.create_table())?;
-                    let handlers = compute_if_absent(&events, event_key.as_str(), || lua_ctx.create_table())?;
+                    let handlers = compute_if_absent(&events, event_name(&event_key), || lua_ctx.create_table())?;
                     handlers.set(new_id, f)?;
                 } else {
                     println!("Failed to register event {:?}", pair);
@@ -54,6 +183,68 @@ impl LuaUserData for Global {
             }
             Ok(new_id)
         });
+        // the entity is handed back right away, but it only has a position until its prefab loads in a later frame. Its
+        // script hasn't run yet, and it has no sprite or movement of its own
+        methods.add_method("spawn", |lua_ctx, this, (prefab, x, y, z): (String, i32, i32, i32)| {
+            let mut world = this.world.lock();
+            let level = Global::local_level(lua_ctx, &world)?;
+            match world.spawn(prefab.clone(), Pos { x, y, z }, level) {
+                Ok(entity) => Ok(LuaEntity::new(entity)),
+                Err(e)     => Err(LuaError::RuntimeError(format!("Can't spawn `{}`, {}", prefab, e))),
+            }
+        });
+        methods.add_method("unregister", |lua_ctx, _, id: usize| {
+            // returns if anything was registered with that id
+            let mut is_found = false;
+            let events: Option<LuaTable> = get_if_present(&lua_ctx.globals(), Global::EVENTS_VAR_NAME)?;
+            if let Some(events) = events {
+                for pair in events.pairs::<String, LuaTable>() {
+                    let (_, handlers) = pair?;
+                    if handlers.contains_key(id)? {
+                        handlers.set(id, LuaValue::Nil)?;
+                        is_found = true;
+                    }
+                }
+            }
+            Ok(is_found)
+        });
+        methods.add_method("emit", |lua_ctx, _, (key, payload): (String, LuaValue)| {
+            Global::dispatch(lua_ctx, &key, payload)
+        });
+        // sends to every entity with an on_message handler, other than the one whose script is running
+        methods.add_method("broadcast", |lua_ctx, this, (name, payload, delivery): (String, StoredValue, Option<String>)| {
+            let sender: Option<LuaEntity> = lua_ctx.globals().get(LuaEntity::LUA_ENTITY_NAME)?;
+            let message = Message { sender: sender.map(|e| e.entity), target: None, name, payload };
+            send(lua_ctx, &this.mailbox, message, delivery)
+        });
+        methods.add_method("snapshot", |lua_ctx, _, name: String| {
+            let marked: LuaTable = lua_ctx.globals().get(Global::SNAPSHOT_VAR_NAME)?;
+            marked.set(name, true)
+        });
+        methods.add_method("interrupt", |_, this, ()| {
+            this.interrupted.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        // Random
+        // takes the same arguments as math.random, plus the stream to draw from
+        methods.add_method("random", |_, this, (low, high, stream): (Option<i64>, Option<i64>, Option<String>)| {
+            let stream = stream_name(&stream)?;
+            let (m, n) = match (low, high) {
+                (None, _)          => return Ok(LuaValue::Number(this.random.fraction(stream))),
+                (Some(m), None)    => (1, m),
+                (Some(m), Some(n)) => (m, n),
+            };
+            if m > n {
+                return Err(LuaError::RuntimeError(format!("Can't pick a random number from {} to {}, the interval is empty", m, n)));
+            }
+            Ok(LuaValue::Integer(this.random.range(stream, m, n)))
+        });
+        methods.add_method("chance", |_, this, (p, stream): (f64, Option<String>)| {
+            Ok(this.random.chance(stream_name(&stream)?, p))
+        });
+        methods.add_method("seed", |_, this, ()| {
+            Ok(this.random.seed.to_string())
+        });
         // Debug
         methods.add_method("is_debug", |_, this, ()| {
             Ok(this.is_debug)
@@ -65,50 +256,141 @@ impl LuaUserData for Global {
             Ok(())
         });
         // Getters and Setters
-        methods.add_method("player", |_, _, _idx: i32| {
-            Err::<(), LuaError>(LuaError::RuntimeError("global:player(idx) not implemented".to_string()))
+        methods.add_method("player", |_, this, idx: Option<usize>| {
+            // numbered from 1 like everything else in Lua, in id order
+            let idx = idx.unwrap_or(1);
+            Ok(idx.checked_sub(1).and_then(|i| this.world.lock().players.get(i).cloned()).map(LuaEntity::new))
+        });
+        methods.add_method("players", |_, this, ()| {
+            Ok(this.world.lock().players.iter().cloned().map(LuaEntity::new).collect::<Vec<_>>())
         });
         methods.add_method("turn_count", |_, this, ()| {
             Ok(this.turn_count)
         });
+        // Map
+        methods.add_method("level", |lua_ctx, this, ()| {
+            let world = this.world.lock();
+            let level = Global::local_level(lua_ctx, &world)?;
+            let info = world.levels.get(&level).cloned().unwrap_or_default();
+            let (width, height, layers) = world.grids.get(&level).map_or((0, 0, 0), |grid| grid.dimensions());
+            let t = lua_ctx.create_table()?;
+            t.set("title", info.title)?;
+            t.set("subtitle", info.subtitle)?;
+            t.set("index", info.level_idx)?;
+            t.set("width", width)?;
+            t.set("height", height)?;
+            t.set("layers", layers)?;
+            Ok(t)
+        });
+        methods.add_method("tile", |lua_ctx, this, (x, y, z): (i32, i32, i32)| {
+            let world = this.world.lock();
+            let level = Global::local_level(lua_ctx, &world)?;
+            // the kind of tile, and how much it hurts if it's damaging
+            Ok(match world.tile(level, &Pos { x, y, z }).unwrap_or_default() {
+                PosState::None        => ("None", None),
+                PosState::Solid       => ("Solid", None),
+                PosState::Floorless   => ("Floorless", None),
+                PosState::Water       => ("Water", None),
+                PosState::Damaging(d) => ("Damaging", Some(d)),
+            })
+        });
+        methods.add_method("set_tile", |lua_ctx, this, (pos, state): (Pos, LuaValue)| {
+            let state = parse_tile(state)?;
+            let mut world = this.world.lock();
+            let level = Global::local_level(lua_ctx, &world)?;
+            if world.set_tile(level, pos, state) {
+                Ok(())
+            } else {
+                Err(LuaError::RuntimeError(format!("Can't set tile at {:?}, it's outside the level", pos)))
+            }
+        });
+        methods.add_method("entities_at", |lua_ctx, this, pos: Pos| {
+            let world = this.world.lock();
+            let level = Global::local_level(lua_ctx, &world)?;
+            Ok(world.entities_at(level, &pos).into_iter().map(LuaEntity::new).collect::<Vec<_>>())
+        });
+        methods.add_method("entities_in_radius", |lua_ctx, this, (pos, radius, filter): (Pos, i32, Option<LuaFunction>)| {
+            let found = {
+                let world = this.world.lock();
+                let level = Global::local_level(lua_ctx, &world)?;
+                world.entities_in_radius(level, &pos, radius)
+            };
+            // the world is unlocked first, since the filter can call back into it
+            let mut entities = Vec::new();
+            for entity in found {
+                let is_kept = match &filter {
+                    Some(f) => f.call::<_, bool>(LuaEntity::new(entity))?,
+                    None    => true,
+                };
+                if is_kept {
+                    entities.push(LuaEntity::new(entity));
+                }
+            }
+            Ok(entities)
+        });
+        methods.add_method("line", |_, _, (from, to): (Pos, Pos)| {
+            Ok(from.line_to(&to))
+        });
         // Variables
-        methods.add_method("get", |lua_ctx, _, key: String| {
-            let vars: LuaTable = lua_ctx.globals().get(Global::VAR_VALUES_VAR_NAME).expect("Missing global var values table");
-            vars.get::<_, LuaValue>(key)
-        });
-        methods.add_method("set", |lua_ctx, this, (key, val): (String, LuaValue)| {
-            let vars: LuaTable = lua_ctx.globals().get(Global::VAR_VALUES_VAR_NAME).expect("Missing global var values table");
-            if let Some(handler_ids) = this.var_watchers.get(&key) {
-                let old_value: LuaValue = vars.get(key.clone())?;
+        methods.add_method("get", |_, this, key: String| {
+            Ok(this.vars.get(&key))
+        });
+        methods.add_method("set", |lua_ctx, this, (key, val): (String, StoredValue)| {
+            // watchers run before the value changes, with the old and new values. The value is still set if one fails
+            let watcher_ids = this.vars.watchers(&key);
+            let mut result = Ok(());
+            if !watcher_ids.is_empty() {
+                let old_value = this.vars.get(&key);
                 let handlers: LuaTable = lua_ctx.globals().get(Global::VAR_HANDLERS_VAR_NAME)?;
                 let table: LuaTable = handlers.get(key.clone())?;
-                for id in handler_ids {
-                    table.get::<_, LuaFunction>(id.clone()).unwrap().call::<_, ()>((old_value.clone(), val.clone()))?;
+                result = call_handlers(lua_ctx, &table, (old_value, val.clone()).to_lua_multi(lua_ctx)?.into_vec());
+                // watchers that failed too often were dropped from the table
+                for id in watcher_ids {
+                    if !table.contains_key(id)? {
+                        this.vars.unwatch(&key, id);
+                    }
                 }
             }
-            vars.set(key, val).unwrap();
-            Ok(())
+            this.vars.set(&key, val);
+            result
         });
-        methods.add_method_mut("unwatch", |lua_ctx, this, (key, handler_id): (String, usize)| {
-            if let Some(set) = this.var_watchers.get_mut(&key) {
-                set.remove(&handler_id);
+        methods.add_method("unwatch", |lua_ctx, this, (key, handler_id): (String, usize)| {
+            if this.vars.unwatch(&key, handler_id) {
                 let handlers: LuaTable = lua_ctx.globals().get(Global::VAR_HANDLERS_VAR_NAME)?;
                 let table: LuaTable = handlers.get(key)?;
-                table.set(handler_id, None::<i32>)?;
+                table.set(handler_id, LuaValue::Nil)?;
             }
             Ok(())
         });
-        methods.add_method_mut("watch", |lua_ctx, this, (key, watcher): (String, LuaFunction)| {
+        methods.add_method("watch", |lua_ctx, this, (key, watcher): (String, LuaFunction)| {
             let new_id = this.next_id();
-            this.var_watchers.entry(key.clone()).or_insert_with(|| HashSet::new()).insert(new_id);
+            this.vars.watch(&key, new_id);
             let handlers: LuaTable = lua_ctx.globals().get(Global::VAR_HANDLERS_VAR_NAME)?;
-            if !handlers.contains_key(key.clone())? {
-                let table = lua_ctx.create_table()?;
-                handlers.set(key.clone(), table)?;
-            }
-            let table: LuaTable = handlers.get(key)?;
+            let table = compute_if_absent(&handlers, key, || lua_ctx.create_table())?;
             table.set(new_id, watcher)?;
             Ok(new_id)
         });
     }
-}
\ No newline at end of file
+}
+
+/// A tile kind such as `"Solid"`, or a table like `{Damaging = 2}`
+fn parse_tile(state: LuaValue) -> LuaResult<PosState> {
+    match state {
+        LuaValue::String(s) => {
+            let s = s.to_str()?;
+            ron::de::from_str::<PosState>(s).map_err(|_| LuaError::RuntimeError(format!("`{}` is not a kind of tile", s)))
+        },
+        LuaValue::Table(t) => match t.get::<_, Option<f32>>("Damaging")? {
+            Some(damage) => Ok(PosState::Damaging(damage)),
+            None         => Err(LuaError::RuntimeError("Expected a table like {Damaging = 2} for a tile".to_string())),
+        },
+        other => Err(LuaError::RuntimeError(format!("Expected a tile kind or table for a tile, got {}", other.type_name()))),
+    }
+}
+
+/// Lua state saved with each turn so it can be rewound along with the world
+#[derive(Clone, Debug, Default)]
+pub struct LuaSnapshot {
+    pub vars:   SavedVars,
+    pub marked: Vec<(String, StoredValue)>, // Lua globals scripts asked to be included
+}
@@ -0,0 +1,126 @@
+use bevy::prelude::Entity;
+use rlua::prelude::*;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::lua::entity::*;
+use crate::lua::global::*;
+use crate::lua::util::*;
+use crate::lua::value::*;
+
+/// How deep `on_message` handlers can go sending immediate messages of their own, so two scripts can't loop forever
+pub const MAX_MESSAGE_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Delivery {
+    Immediate,
+    Queued, // handled at the start of the next turn phase
+}
+
+impl Delivery {
+    pub fn from_name(name: &str) -> Option<Delivery> {
+        match name {
+            "immediate" => Some(Delivery::Immediate),
+            "queued"    => Some(Delivery::Queued),
+            _           => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub sender:  Option<Entity>,
+    pub target:  Option<Entity>, // everything with an `on_message` handler if there's no target
+    pub name:    String,
+    pub payload: StoredValue,
+}
+
+#[derive(Default)]
+pub struct MailboxState {
+    pub queued: Vec<Message>,
+    pub depth:  usize, // immediate messages currently being handled
+}
+
+/// Messages between entities waiting to be delivered, shared by every copy of `Global`
+#[derive(Clone, Default)]
+pub struct Mailbox(Arc<Mutex<MailboxState>>);
+
+impl Mailbox {
+    pub fn lock(&self) -> MutexGuard<MailboxState> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn queue(&self, message: Message) {
+        self.lock().queued.push(message);
+    }
+
+    pub fn take_queued(&self) -> Vec<Message> {
+        std::mem::take(&mut self.lock().queued)
+    }
+}
+
+/// Delivers a message from Lua right away, or queues it if asked to with `"queued"`
+pub fn send(lua_ctx: LuaContext, mailbox: &Mailbox, message: Message, delivery: Option<String>) -> LuaResult<()> {
+    let delivery = match delivery {
+        Some(name) => Delivery::from_name(&name).ok_or_else(|| LuaError::RuntimeError(format!("`{}` is not a delivery, expected \"immediate\" or \"queued\"", name)))?,
+        None       => Delivery::Immediate,
+    };
+    match delivery {
+        Delivery::Immediate => deliver(lua_ctx, mailbox, &message),
+        Delivery::Queued    => {
+            mailbox.queue(message);
+            Ok(())
+        },
+    }
+}
+
+/// Runs the `on_message(name, payload, sender)` handlers of a message's target, or of every entity listening if it has
+/// none, with `local_entity` set to the receiver. Whatever `local_entity` was before is put back afterwards
+pub fn deliver(lua_ctx: LuaContext, mailbox: &Mailbox, message: &Message) -> LuaResult<()> {
+    let previous: LuaValue = lua_ctx.globals().get(LuaEntity::LUA_ENTITY_NAME)?;
+    {
+        let mut state = mailbox.lock();
+        if state.depth >= MAX_MESSAGE_DEPTH {
+            return Err(LuaError::RuntimeError(format!("Message `{}` not sent, more than {} messages were sent from inside on_message", message.name, MAX_MESSAGE_DEPTH)));
+        }
+        state.depth += 1;
+    }
+    let result = run_message_handlers(lua_ctx, message);
+    lua_ctx.globals().set(LuaEntity::LUA_ENTITY_NAME, previous)?;
+    mailbox.lock().depth -= 1;
+    result
+}
+
+fn run_message_handlers(lua_ctx: LuaContext, message: &Message) -> LuaResult<()> {
+    let entities: Option<LuaTable> = get_if_present(&lua_ctx.globals(), LuaEntity::ENTITY_EVENTS_VAR_NAME)?;
+    let entities = match entities {
+        Some(entities) => entities,
+        None           => return Ok(()),
+    };
+    let keys = match message.target {
+        Some(target) => vec![LuaEntity::key(target)],
+        None         => {
+            // broadcasts reach listeners in id order, and never go back to the sender
+            let sender = message.sender.map(LuaEntity::key);
+            let mut keys: Vec<i64> = entities.clone().pairs::<i64, LuaTable>()
+                .filter_map(|pair| pair.ok())
+                .filter(|(key, events)| Some(*key) != sender && events.contains_key(EntityEvent::OnMessage as u8).unwrap_or(false))
+                .map(|(key, _)| key)
+                .collect();
+            keys.sort_by_key(|key| Entity::from_bits(*key as u64).id());
+            keys
+        },
+    };
+    let mut result = Ok(());
+    for key in keys {
+        let handlers: Option<LuaTable> = get_if_present::<_, LuaTable>(&entities, key)?
+            .and_then(|t: LuaTable| get_if_present(&t, EntityEvent::OnMessage as u8).unwrap());
+        if let Some(handlers) = handlers {
+            lua_ctx.globals().set(LuaEntity::LUA_ENTITY_NAME, LuaEntity::new(Entity::from_bits(key as u64)))?;
+            let args = (message.name.clone(), message.payload.clone(), message.sender.map(LuaEntity::new)).to_lua_multi(lua_ctx)?;
+            result = result.and(call_handlers(lua_ctx, &handlers, args.into_vec()));
+            let receiver: LuaEntity = lua_ctx.globals().get(LuaEntity::LUA_ENTITY_NAME)?;
+            Global::world(lua_ctx)?.lock().register_events(receiver.entity, receiver.events_registered);
+        }
+    }
+    result
+}
@@ -1,10 +1,20 @@
 pub mod global;
 pub mod entity;
+pub mod message;
 pub mod script;
 pub mod types;
 pub mod util;
+pub mod value;
+pub mod vars;
+pub mod world;
 pub use self::entity::EntityEvent;
+pub use self::entity::EventArg;
 pub use self::entity::LuaEntity;
+pub use self::global::GlobalEvent;
+pub use self::global::LuaSnapshot;
 pub use self::script::LuaResource;
 pub use self::script::LuaScript;
-pub use self::script::LuaScriptLoader;
\ No newline at end of file
+pub use self::script::LuaScriptLoader;
+pub use self::value::StoredValue;
+pub use self::vars::VarStore;
+pub use self::world::WorldView;
\ No newline at end of file
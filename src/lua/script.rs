@@ -1,31 +1,66 @@
 use bevy::{
-    asset::{AssetLoader, LoadContext, LoadedAsset},
+    asset::{AssetLoader, HandleUntyped, LoadContext, LoadedAsset},
+    ecs::entity::Entity,
     reflect::TypeUuid,
     utils::BoxedFuture,
 };
 use rlua::{Lua, prelude::*, StdLib};
 use std::{borrow::BorrowMut, sync::Mutex};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 
+use crate::lua::entity::*;
 use crate::lua::global::*;
+use crate::lua::message::*;
 use crate::lua::types::*;
+use crate::lua::util::*;
+
+pub const SCRIPT_LOG_PATH: &'static str = "logs/scripts.log";
+pub const MODULE_DIR: &'static str = "scripts/lib";
+const MODULE_ROOT: &'static str = "scripts/";
 
 #[derive(Debug, Clone, TypeUuid)]
 #[uuid = "f63d791c-ed06-4a84-91ef-f01b640799fe"]
-pub struct LuaScript(pub Vec<u8>);
+pub struct LuaScript {
+    pub path:   String, // shown in errors along with the line number
+    pub source: Vec<u8>,
+}
 
 impl AsRef<[u8]> for LuaScript {
     fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+        self.source.as_ref()
     } 
 }
 
+/// The shared libraries scripts can `require`, which have to be loaded before any prefab's script runs
+#[derive(Default)]
+pub struct LuaModules {
+    pub handles:   Vec<HandleUntyped>,
+    pub is_loaded: bool,
+}
+
+/// How edited scripts are run again
+#[derive(Default)]
+pub struct ReloadSettings {
+    pub is_resetting_data: bool, // `local_entity:data()` starts over too, instead of being kept
+}
+
+/// A script failing somewhere, kept until it's been shown
+#[derive(Clone, Debug)]
+pub struct ScriptError {
+    pub context: String, // what was running, such as an event and the entity it ran for
+    pub message: String,
+}
+
 #[derive(Default)]
 pub struct LuaScriptLoader;
 
 impl AssetLoader for LuaScriptLoader {
     fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
         Box::pin(async move {
-            load_context.set_default_asset(LoadedAsset::new(LuaScript(bytes.iter().cloned().collect())));
+            let path = load_context.path().to_string_lossy().to_string();
+            load_context.set_default_asset(LoadedAsset::new(LuaScript { path, source: bytes.iter().cloned().collect() }));
             Ok(())
         })
     }
@@ -36,6 +71,7 @@ impl AssetLoader for LuaScriptLoader {
 pub struct LuaResource {
     lua:        Mutex<Lua>,
     pub global: Global,
+    errors:     Vec<ScriptError>,
 }
 
 impl Default for LuaResource {
@@ -47,24 +83,28 @@ impl Default for LuaResource {
         LuaResource {
             lua: Mutex::new(lua),
             global,
+            errors: Vec::new(),
         }
     }
 }
 
 impl LuaResource {
-    pub fn exec_script<S: ?Sized>(&mut self, source: &S) where S: AsRef<[u8]> {
+    /// Runs a script, with `name` used for the chunk name so errors point back to it
+    pub fn exec_script<S: ?Sized>(&mut self, name: &str, source: &S) -> LuaResult<()> where S: AsRef<[u8]> {
         let mut lua_guard = self.lua.lock().unwrap();
-        let _ = lua_guard.borrow_mut().context(|lua_ctx| {
-            lua_ctx.load(source).exec()
-        });
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            lua_ctx.load(source).set_name(&chunk_name(name))?.exec()
+        })
     }
 
-    pub fn exec_script_with_instance<S: ?Sized, I: LuaInstance>(&mut self, source: &S, instance: I) -> LuaResult<I> where S: AsRef<[u8]> {
+    pub fn exec_script_with_instance<I: LuaInstance>(&mut self, script: &LuaScript, instance: I) -> LuaResult<I> {
         let mut lua_guard = self.lua.lock().unwrap();
         lua_guard.borrow_mut().context(|lua_ctx| {
             instance.init(lua_ctx)?;
-            lua_ctx.load(source).exec()?;
-            I::finalize(lua_ctx)
+            // always finalized, so a failed script doesn't leave its instance behind for the next one
+            let result = lua_ctx.load(script).set_name(&chunk_name(&script.path)).and_then(|chunk| chunk.exec());
+            let instance = I::finalize(lua_ctx)?;
+            result.map(|_| instance)
         })
     }
 
@@ -72,15 +112,156 @@ impl LuaResource {
         let mut lua_guard = self.lua.lock().unwrap();
         lua_guard.borrow_mut().context(|lua_ctx| {
             instance.clone().init(lua_ctx)?;
-            instance.run_handlers(lua_ctx, key)?;
-            L::finalize(lua_ctx)
+            let result = instance.run_handlers(lua_ctx, key);
+            let instance = L::finalize(lua_ctx)?;
+            result.map(|_| instance)
         })
     }
 
+    /// Runs an entity event. Handlers registered while it runs are added to the entity once the world view is applied,
+    /// the same as from on_message
+    pub fn run_entity_event(&mut self, event: EntityEvent, instance: LuaEntity) -> LuaResult<LuaEntity> {
+        let run_results = self.run_event(event, instance)?;
+        self.global.world.lock().register_events(run_results.entity, run_results.events_registered);
+        Ok(run_results)
+    }
+
+    /// Runs an entity's script again, such as after it's been edited, replacing the handlers it registered as it ran.
+    /// Handlers registered later, from its events, are kept
+    pub fn reload_script(&mut self, script: &LuaScript, entity: Entity, settings: &ReloadSettings) -> LuaResult<LuaEntity> {
+        {
+            let mut lua_guard = self.lua.lock().unwrap();
+            lua_guard.borrow_mut().context(|lua_ctx| {
+                LuaEntity::clear_script_handlers(lua_ctx, entity)?;
+                if settings.is_resetting_data {
+                    LuaEntity::clear_data(lua_ctx, entity)?;
+                }
+                Ok(())
+            })?;
+        }
+        let run_results = self.exec_script_with_instance(script, LuaEntity::for_script(entity))?;
+        let mut lua_guard = self.lua.lock().unwrap();
+        let events_registered = lua_guard.borrow_mut().context(|lua_ctx| {
+            LuaEntity::registered_events(lua_ctx, entity)
+        })?;
+        Ok(LuaEntity { events_registered, ..run_results })
+    }
+
+    /// Makes a script under `scripts/` available to `require`, replacing any version of it already required
+    pub fn set_module(&mut self, script: &LuaScript) -> LuaResult<()> {
+        let name = match module_name(&script.path) {
+            Some(name) => name,
+            None       => return Ok(()),
+        };
+        let mut lua_guard = self.lua.lock().unwrap();
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            let package: LuaTable = lua_ctx.globals().get("package")?;
+            let preload: LuaTable = package.get("preload")?;
+            let loaded: LuaTable  = package.get("loaded")?;
+            preload.set(name.clone(), lua_ctx.load(script).set_name(&chunk_name(&script.path))?.into_function()?)?;
+            loaded.set(name, LuaValue::Nil)
+        })
+    }
+
+    /// Drops an entity's handlers and `local_entity:data()` once it's gone
+    pub fn forget_entity(&mut self, entity: Entity) -> LuaResult<()> {
+        let mut lua_guard = self.lua.lock().unwrap();
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            LuaEntity::clear_handlers(lua_ctx, entity)?;
+            LuaEntity::clear_data(lua_ctx, entity)
+        })
+    }
+
+    /// Runs the handlers registered through `global:register` for an event
+    pub fn run_global_event(&mut self, event: GlobalEvent) -> LuaResult<()> {
+        let mut lua_guard = self.lua.lock().unwrap();
+        let global = &self.global;
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            global.run_handlers(lua_ctx, event)
+        })
+    }
+
+    /// Delivers the messages scripts queued since this was last called, reporting any handler that fails
+    pub fn deliver_queued_messages(&mut self) {
+        let messages = self.global.mailbox.take_queued();
+        if messages.is_empty() {
+            return;
+        }
+        let mut errors = Vec::new();
+        {
+            let mut lua_guard = self.lua.lock().unwrap();
+            let mailbox = &self.global.mailbox;
+            lua_guard.borrow_mut().context(|lua_ctx| {
+                for message in messages.iter() {
+                    if let Err(e) = deliver(lua_ctx, mailbox, message) {
+                        errors.push((message, e));
+                    }
+                }
+            });
+        }
+        for (message, e) in errors {
+            let receiver = message.target.map_or("broadcast".to_string(), |target| format!("{:?}", target));
+            self.report_error(&format!("on_message `{}` for {}", message.name, receiver), &e);
+        }
+    }
+
+    pub fn take_snapshot(&mut self) -> LuaResult<LuaSnapshot> {
+        let mut lua_guard = self.lua.lock().unwrap();
+        let global = &self.global;
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            global.take_snapshot(lua_ctx)
+        })
+    }
+
+    pub fn restore_snapshot(&mut self, snapshot: &LuaSnapshot) -> LuaResult<()> {
+        let mut lua_guard = self.lua.lock().unwrap();
+        let global = &self.global;
+        lua_guard.borrow_mut().context(|lua_ctx| {
+            global.restore_snapshot(lua_ctx, snapshot)
+        })
+    }
+
+    /// Logs an error from a script and keeps it to be shown in game, instead of stopping the game
+    pub fn report_error(&mut self, context: &str, error: &LuaError) {
+        let error = ScriptError { context: context.to_string(), message: describe_error(error) };
+        println!("Error in {}: {}", error.context, error.message);
+        if let Err(e) = append_to_log(&error) {
+            println!("Failed to write to {}: {}", SCRIPT_LOG_PATH, e);
+        }
+        self.errors.push(error);
+    }
+
+    /// Errors reported since this was last called
+    pub fn take_errors(&mut self) -> Vec<ScriptError> {
+        std::mem::take(&mut self.errors)
+    }
+
     pub fn sync(&mut self) {
         let mut lua_guard = self.lua.lock().unwrap();
         lua_guard.borrow_mut().context(|lua_ctx| {
             lua_ctx.globals().set("global", self.global.clone())
         }).expect("failed to sync LuaResource");
     }
-}
\ No newline at end of file
+}
+
+/// `scripts/lib/ai.lua` is required as `lib.ai`
+pub fn module_name(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let name = path.strip_prefix(MODULE_ROOT)?.strip_suffix(".lua")?;
+    Some(name.replace('/', "."))
+}
+
+/// Lua treats names starting with `@` as file names, and reports errors as `path:line: message`
+fn chunk_name(path: &str) -> String {
+    format!("@{}", path)
+}
+
+fn append_to_log(error: &ScriptError) -> anyhow::Result<()> {
+    let path = Path::new(SCRIPT_LOG_PATH);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "Error in {}: {}", error.context, error.message)?;
+    Ok(())
+}
@@ -20,4 +20,66 @@ pub fn get_if_present<'lua, K, V>(table: &LuaTable<'lua>, key: K) -> LuaResult<O
     } else {
         Ok(None)
     }
-}
\ No newline at end of file
+}
+
+/// How many times in a row a handler can fail before it's dropped
+pub const MAX_HANDLER_FAILURES: i32 = 3;
+pub const HANDLER_FAILURES_VAR_NAME: &'static str = "_H_ERR";
+
+/// Calls an event handler, removing it from `handlers` once it has failed several times in a row so one broken script
+/// doesn't fill the log every turn
+pub fn call_handler<'lua, K>(lua_ctx: LuaContext<'lua>, handlers: &LuaTable<'lua>, key: K, f: LuaFunction<'lua>, args: LuaMultiValue<'lua>) -> LuaResult<()>
+    where K: ToLua<'lua> {
+    // weak keys, so handlers that are unregistered some other way can still be collected
+    let failures: LuaTable = compute_if_absent(&lua_ctx.globals(), HANDLER_FAILURES_VAR_NAME, || {
+        let table = lua_ctx.create_table()?;
+        let meta = lua_ctx.create_table()?;
+        meta.set("__mode", "k")?;
+        table.set_metatable(Some(meta));
+        Ok(table)
+    })?;
+    match f.call::<_, ()>(args) {
+        Ok(()) => failures.set(f, LuaValue::Nil),
+        Err(e) => {
+            let count = failures.get::<_, Option<i32>>(f.clone())?.unwrap_or(0) + 1;
+            if count >= MAX_HANDLER_FAILURES {
+                handlers.set(key, LuaValue::Nil)?;
+                failures.set(f, LuaValue::Nil)?;
+                Err(LuaError::RuntimeError(format!("{}\n(handler disabled after failing {} times in a row)", describe_error(&e), count)))
+            } else {
+                failures.set(f, count)?;
+                Err(e)
+            }
+        },
+    }
+}
+
+/// Calls every handler in `handlers` in id order, which is the order they were registered in. They all get to run even if
+/// one fails, and the first error is passed on
+pub fn call_handlers<'lua>(lua_ctx: LuaContext<'lua>, handlers: &LuaTable<'lua>, args: Vec<LuaValue<'lua>>) -> LuaResult<()> {
+    let mut ids = Vec::new();
+    for pair in handlers.clone().pairs::<i64, LuaFunction>() {
+        match pair {
+            Ok((id, _)) => ids.push(id),
+            Err(e)      => println!("Skipping a handler that isn't a function: {}", e),
+        }
+    }
+    ids.sort();
+    let mut result = Ok(());
+    for id in ids {
+        // an earlier handler can unregister a later one
+        if let Some(f) = handlers.get::<_, Option<LuaFunction>>(id)? {
+            result = result.and(call_handler(lua_ctx, handlers, id, f, LuaMultiValue::from_vec(args.clone())));
+        }
+    }
+    result
+}
+
+/// The message Lua gave, which starts with the script and line it came from, along with where errors raised by Rust
+/// callbacks were called from
+pub fn describe_error(e: &LuaError) -> String {
+    match e {
+        LuaError::CallbackError { traceback, cause } => format!("{}\n{}", describe_error(cause), traceback),
+        e => e.to_string(),
+    }
+}
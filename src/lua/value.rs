@@ -0,0 +1,77 @@
+use rlua::prelude::*;
+use serde::{Serialize, Deserialize};
+
+const MAX_TABLE_DEPTH: usize = 32;
+
+/// A Lua value copied out of the Lua state, so it can be kept after the context is gone. Functions and userdata can't be stored
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum StoredValue {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Table(Vec<(StoredValue, StoredValue)>),
+}
+
+impl StoredValue {
+    fn from_lua_at_depth(lua_value: LuaValue, depth: usize) -> LuaResult<StoredValue> {
+        match lua_value {
+            LuaValue::Nil        => Ok(StoredValue::Nil),
+            LuaValue::Boolean(b) => Ok(StoredValue::Boolean(b)),
+            LuaValue::Integer(i) => Ok(StoredValue::Integer(i)),
+            LuaValue::Number(n)  => Ok(StoredValue::Number(n)),
+            LuaValue::String(s)  => Ok(StoredValue::String(s.to_str()?.to_string())),
+            LuaValue::Table(t) if depth < MAX_TABLE_DEPTH => {
+                let mut pairs = Vec::new();
+                for pair in t.pairs::<LuaValue, LuaValue>() {
+                    let (key, val) = pair?;
+                    pairs.push((StoredValue::from_lua_at_depth(key, depth + 1)?, StoredValue::from_lua_at_depth(val, depth + 1)?));
+                }
+                Ok(StoredValue::Table(pairs))
+            },
+            other => Err(LuaError::FromLuaConversionError {
+                from:    other.type_name(),
+                to:      "StoredValue",
+                message: Some("only nil, booleans, numbers, strings and tables of them can be stored".to_string()),
+            }),
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for StoredValue {
+    fn from_lua(lua_value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<StoredValue> {
+        StoredValue::from_lua_at_depth(lua_value, 0)
+    }
+}
+
+impl<'lua> ToLua<'lua> for StoredValue {
+    fn to_lua(self, lua_ctx: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        match self {
+            StoredValue::Nil        => Ok(LuaValue::Nil),
+            StoredValue::Boolean(b) => Ok(LuaValue::Boolean(b)),
+            StoredValue::Integer(i) => Ok(LuaValue::Integer(i)),
+            StoredValue::Number(n)  => Ok(LuaValue::Number(n)),
+            StoredValue::String(s)  => s.to_lua(lua_ctx),
+            StoredValue::Table(pairs) => {
+                let table = lua_ctx.create_table()?;
+                for (key, val) in pairs {
+                    table.set(key, val)?;
+                }
+                Ok(LuaValue::Table(table))
+            },
+        }
+    }
+}
+
+impl From<i64> for StoredValue {
+    fn from(n: i64) -> StoredValue {
+        StoredValue::Integer(n)
+    }
+}
+
+impl From<&str> for StoredValue {
+    fn from(s: &str) -> StoredValue {
+        StoredValue::String(s.to_string())
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::lua::value::*;
+
+pub const VARS_PATH: &'static str = "saves/vars.ron";
+
+/// What's written to a save; watchers are Lua functions, so they're registered again by the scripts that use them
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SavedVars {
+    pub values: BTreeMap<String, StoredValue>,
+}
+
+#[derive(Default)]
+pub struct VarState {
+    pub values:   BTreeMap<String, StoredValue>,
+    pub watchers: BTreeMap<String, BTreeSet<usize>>, // ids of the functions in `_G_HDL[key]`
+}
+
+/// Variables set with `global:set`, kept on the Rust side so every copy of `Global` shares them, and so they can be
+/// saved and read by systems
+#[derive(Clone, Default)]
+pub struct VarStore(Arc<Mutex<VarState>>);
+
+impl VarStore {
+    pub fn lock(&self) -> MutexGuard<VarState> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn get(&self, key: &str) -> StoredValue {
+        self.lock().values.get(key).cloned().unwrap_or(StoredValue::Nil)
+    }
+
+    /// Returns the old value. Setting nil removes the variable
+    pub fn set(&self, key: &str, val: StoredValue) -> StoredValue {
+        let mut state = self.lock();
+        let old = match val {
+            StoredValue::Nil => state.values.remove(key),
+            val              => state.values.insert(key.to_string(), val),
+        };
+        old.unwrap_or(StoredValue::Nil)
+    }
+
+    pub fn watchers(&self, key: &str) -> Vec<usize> {
+        self.lock().watchers.get(key).map_or(Vec::new(), |ids| ids.iter().cloned().collect())
+    }
+
+    pub fn watch(&self, key: &str, id: usize) {
+        self.lock().watchers.entry(key.to_string()).or_insert_with(|| BTreeSet::new()).insert(id);
+    }
+
+    /// Returns if the watcher was there
+    pub fn unwatch(&self, key: &str, id: usize) -> bool {
+        self.lock().watchers.get_mut(key).map_or(false, |ids| ids.remove(&id))
+    }
+
+    pub fn to_saved(&self) -> SavedVars {
+        SavedVars { values: self.lock().values.clone() }
+    }
+
+    /// Replaces every value, without notifying watchers
+    pub fn restore(&self, saved: SavedVars) {
+        self.lock().values = saved.values;
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, ron::ser::to_string_pretty(&self.to_saved(), ron::ser::PrettyConfig::new())?)?;
+        Ok(())
+    }
+
+    pub fn load(&self, path: &Path) -> anyhow::Result<()> {
+        self.restore(ron::de::from_bytes::<SavedVars>(&fs::read(path)?)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use bevy::prelude::*;
+use enumset::*;
+use rlua::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::data::action::*;
+use crate::data::color::*;
+use crate::data::level::*;
+use crate::lua::entity::*;
+use crate::lua::value::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct EntityView {
+    pub pos:      Pos,
+    pub level:    Entity,
+    pub movement: EnumSet<Movement>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Relocation {
+    Step(Dir),
+    Teleport,
+}
+
+/// A script moving an entity, waiting to be applied to its components
+#[derive(Clone, Copy, Debug)]
+pub struct Relocated {
+    pub entity:   Entity,
+    pub level:    Entity,
+    pub from:     Pos,
+    pub to:       Pos,
+    pub movement: EnumSet<Movement>,
+    pub kind:     Relocation,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SpriteView {
+    pub name_to_index: HashMap<String, usize>,
+    pub cur_anim_idx:  usize,
+}
+
+/// A script changing how an entity looks, waiting to be applied to its sprite
+#[derive(Clone, Copy, Debug)]
+pub enum SpriteChange {
+    Anim(usize),
+    Tint(Palette),
+    Frame(u32),
+    Visible(bool),
+}
+
+pub const ENTITY_POOL_SIZE: usize = 32;
+
+/// A prefab a script asked for, to be spawned on an entity taken from the pool
+#[derive(Clone, Debug)]
+pub struct Spawn {
+    pub entity: Entity,
+    pub level:  Entity,
+    pub pos:    Pos,
+    pub prefab: String,
+}
+
+/// The parts of the world scripts can see and change, kept in sync with the components they come from
+#[derive(Default)]
+pub struct WorldState {
+    pub entities:       HashMap<Entity, EntityView>,
+    pub grids:          HashMap<Entity, Grid>, // by level entity
+    pub levels:         HashMap<Entity, LevelInfo>,
+    pub players:        Vec<Entity>, // in id order
+    pub sources:        HashMap<Entity, ActionSource>,
+    pub actions:        HashMap<Entity, Action>, // chosen for entities whose actions come from scripts, until their turn
+    pub relocated:      Vec<Relocated>,
+    pub sprites:        HashMap<Entity, SpriteView>, // only for animated sprites
+    pub sprite_changes: Vec<(Entity, SpriteChange)>,
+    pub tile_changes:   Vec<GridChanged>,
+    pub entity_pool:    Vec<Entity>, // reserved ahead of time, so a spawned entity can be handed to the script right away
+    pub spawns:         Vec<Spawn>,
+    pub despawns:       Vec<Entity>,
+    pub registered:     HashMap<Entity, EnumSet<EntityEvent>>, // from inside event handlers, to add to the entity
+}
+
+impl WorldState {
+    /// Adds to the events an entity has handlers for, once its components are next updated
+    pub fn register_events(&mut self, entity: Entity, events: EnumSet<EntityEvent>) {
+        if !events.is_empty() {
+            *self.registered.entry(entity).or_default() |= events;
+        }
+    }
+
+    pub fn pos(&self, entity: Entity) -> Option<Pos> {
+        self.entities.get(&entity).map(|view| view.pos)
+    }
+
+    pub fn can_move(&self, entity: Entity, dir: Dir) -> bool {
+        self.entities.get(&entity)
+            .and_then(|view| self.grids.get(&view.level).map(|grid| grid.can_enter(&view.pos.step(dir), view.movement)))
+            .unwrap_or(false)
+    }
+
+    /// Steps an entity the same way `update_actions` does, returning if it moved
+    pub fn move_entity(&mut self, entity: Entity, dir: Dir) -> bool {
+        let view = match self.entities.get_mut(&entity) {
+            Some(view) => view,
+            None       => return false,
+        };
+        let to = match self.grids.get_mut(&view.level).and_then(|grid| grid.try_move(entity, &view.pos, dir, view.movement)) {
+            Some(to) => to,
+            None     => return false,
+        };
+        self.relocated.push(Relocated { entity, level: view.level, from: view.pos, to, movement: view.movement, kind: Relocation::Step(dir) });
+        view.pos = to;
+        true
+    }
+
+    /// Moves an entity straight to a cell on its level, if the cell is inside it and its movement allows it to be there
+    pub fn teleport(&mut self, entity: Entity, to: Pos) -> bool {
+        let view = match self.entities.get_mut(&entity) {
+            Some(view) => view,
+            None       => return false,
+        };
+        let grid = match self.grids.get_mut(&view.level) {
+            Some(grid) if grid.contains(&to) && grid.can_enter(&to, view.movement) => grid,
+            _ => return false,
+        };
+        grid.remove_occupant(&view.pos, entity);
+        grid.add_occupant(&to, entity, view.movement);
+        self.relocated.push(Relocated { entity, level: view.level, from: view.pos, to, movement: view.movement, kind: Relocation::Teleport });
+        view.pos = to;
+        true
+    }
+
+    /// The level an entity is on, or the first level if there's no entity or it isn't on one
+    pub fn level_of(&self, entity: Option<Entity>) -> Option<Entity> {
+        entity.and_then(|e| self.entities.get(&e).map(|view| view.level))
+            .or_else(|| self.grids.keys().min().cloned())
+    }
+
+    pub fn tile(&self, level: Entity, pos: &Pos) -> Option<PosState> {
+        self.grids.get(&level).map(|grid| grid.get(pos))
+    }
+
+    /// Returns false if the level isn't loaded or the position is outside it
+    pub fn set_tile(&mut self, level: Entity, pos: Pos, state: PosState) -> bool {
+        let grid = match self.grids.get_mut(&level) {
+            Some(grid) if grid.contains(&pos) => grid,
+            _ => return false,
+        };
+        grid.set(&pos, state.clone());
+        self.tile_changes.push(GridChanged { level, pos, state });
+        true
+    }
+
+    /// Everything in a cell, oldest first
+    pub fn entities_at(&self, level: Entity, pos: &Pos) -> Vec<Entity> {
+        let mut entities: Vec<Entity> = self.grids.get(&level)
+            .map(|grid| grid.occupants(pos).iter().map(|o| o.entity).collect())
+            .unwrap_or_default();
+        entities.sort_by_key(|e| e.id());
+        entities
+    }
+
+    /// Everything on the same layer within `radius` cells, nearest first and then oldest first
+    pub fn entities_in_radius(&self, level: Entity, pos: &Pos, radius: i32) -> Vec<Entity> {
+        let mut found: Vec<(i32, Entity)> = self.grids.get(&level)
+            .map(|grid| grid.occupied_cells()
+                .filter(|(p, _)| p.z == pos.z && p.distance(pos) <= radius)
+                .flat_map(|(p, occupants)| occupants.iter().map(move |o| (p.distance(pos), o.entity)))
+                .collect())
+            .unwrap_or_default();
+        found.sort_by_key(|(distance, e)| (distance.clone(), e.id()));
+        found.into_iter().map(|(_, e)| e).collect()
+    }
+
+    /// Places a prefab at `pos` on `level`, or says why it can't be. The prefab's movement isn't known until it loads, so
+    /// the cell has to be one an entity with no special movement could enter
+    pub fn spawn(&mut self, prefab: String, pos: Pos, level: Entity) -> Result<Entity, String> {
+        let grid = self.grids.get_mut(&level).ok_or_else(|| "the level isn't loaded".to_string())?;
+        if !grid.contains(&pos) {
+            return Err(format!("({}, {}, {}) is outside the level", pos.x, pos.y, pos.z));
+        }
+        if !grid.can_enter(&pos, EnumSet::empty()) {
+            return Err(format!("({}, {}, {}) is blocked or occupied", pos.x, pos.y, pos.z));
+        }
+        let entity = self.entity_pool.pop().ok_or_else(|| "too many entities were spawned this frame".to_string())?;
+        grid.add_occupant(&pos, entity, EnumSet::empty());
+        self.entities.insert(entity, EntityView { pos, level, movement: EnumSet::empty() });
+        self.spawns.push(Spawn { entity, level, pos, prefab });
+        Ok(entity)
+    }
+
+    /// Chooses what the entity does on its next turn, replacing anything chosen before. Returns false if its actions
+    /// don't come from scripts
+    pub fn queue_action(&mut self, entity: Entity, action: Action) -> bool {
+        if self.sources.get(&entity).map_or(false, |source| source.is_scripted()) {
+            self.actions.insert(entity, action);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Does whatever was chosen for the entity's turn, returning the action if there was one
+    pub fn take_action(&mut self, entity: Entity) -> Option<Action> {
+        let action = self.actions.remove(&entity)?;
+        if let Action::Move(dir) = action {
+            self.move_entity(entity, dir);
+        }
+        Some(action)
+    }
+
+    /// Returns false if the entity isn't in the world, such as when it's already been despawned
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        let view = match self.entities.remove(&entity) {
+            Some(view) => view,
+            None       => return false,
+        };
+        if let Some(grid) = self.grids.get_mut(&view.level) {
+            grid.remove_occupant(&view.pos, entity);
+        }
+        self.sprites.remove(&entity);
+        self.despawns.push(entity);
+        true
+    }
+
+    /// Returns false if the entity has no animation with this name
+    pub fn set_anim(&mut self, entity: Entity, name: &str) -> bool {
+        let view = match self.sprites.get_mut(&entity) {
+            Some(view) => view,
+            None       => return false,
+        };
+        match view.name_to_index.get(name) {
+            Some(anim_idx) => {
+                view.cur_anim_idx = anim_idx.clone();
+                self.sprite_changes.push((entity, SpriteChange::Anim(anim_idx.clone())));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn current_anim(&self, entity: Entity) -> Option<String> {
+        self.sprites.get(&entity).and_then(|view| {
+            view.name_to_index.iter()
+                .find(|(_, idx)| **idx == view.cur_anim_idx)
+                .map(|(name, _)| name.clone())
+        })
+    }
+
+    pub fn change_sprite(&mut self, entity: Entity, change: SpriteChange) {
+        self.sprite_changes.push((entity, change));
+    }
+}
+
+/// Shared by every copy of `Global`, so scripts and systems see the same world
+#[derive(Clone, Default)]
+pub struct WorldView(Arc<Mutex<WorldState>>);
+
+impl WorldView {
+    pub fn lock(&self) -> MutexGuard<WorldState> {
+        self.0.lock().unwrap()
+    }
+}
+
+/// Positions are passed to and from scripts as `{x = 1, y = 2, z = 0}`, or `{1, 2, 0}`
+impl<'lua> FromLua<'lua> for Pos {
+    fn from_lua(value: LuaValue<'lua>, _: LuaContext<'lua>) -> LuaResult<Pos> {
+        match value {
+            LuaValue::Table(t) => {
+                let coord = |key: &str, idx: i64| -> LuaResult<i32> {
+                    match t.get::<_, Option<i32>>(key)? {
+                        Some(n) => Ok(n),
+                        None    => Ok(t.get::<_, Option<i32>>(idx)?.unwrap_or(0)),
+                    }
+                };
+                Ok(Pos { x: coord("x", 1)?, y: coord("y", 2)?, z: coord("z", 3)? })
+            },
+            other => Err(LuaError::FromLuaConversionError { from: other.type_name(), to: "Pos", message: Some("expected a table".to_string()) }),
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for Pos {
+    fn to_lua(self, lua_ctx: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let t = lua_ctx.create_table()?;
+        t.set("x", self.x)?;
+        t.set("y", self.y)?;
+        t.set("z", self.z)?;
+        Ok(LuaValue::Table(t))
+    }
+}
+
+impl From<Pos> for StoredValue {
+    fn from(pos: Pos) -> StoredValue {
+        StoredValue::Table(vec![
+            (StoredValue::String("x".to_string()), StoredValue::Integer(pos.x as i64)),
+            (StoredValue::String("y".to_string()), StoredValue::Integer(pos.y as i64)),
+            (StoredValue::String("z".to_string()), StoredValue::Integer(pos.z as i64)),
+        ])
+    }
+}
@@ -1,19 +1,38 @@
-use bevy::prelude::*;
+use bevy::{
+    app::ScheduleRunnerSettings,
+    asset::AssetPlugin,
+    input::InputPlugin,
+    prelude::*,
+    transform::TransformPlugin,
+};
 use bevy_ldtk::*;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use data::action::*;
+use data::history::*;
 use data::item::*;
 use data::level::*;
 use data::prefab::*;
+use data::random::*;
+use data::replay::*;
 use data::sprite::*;
 use data::turn::*;
 use lua::script::*;
+use lua::vars::*;
 use system::action::*;
 use system::camera::*;
+use system::history::*;
 use system::level::*;
+use system::overlay::*;
+use system::player::*;
 use system::prefab::*;
+use system::replay::*;
 use system::sprite::*;
+use system::travel::*;
 use system::turn::*;
+use system::world::*;
+use system::tween::*;
 
 mod data;
 mod lua;
@@ -21,36 +40,159 @@ mod system;
 mod util;
 
 fn main() {
-    App::build()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(LdtkPlugin)
+    let arg_value = |name: &str| std::env::args().skip_while(|arg| arg != name).nth(1);
+    let has_flag  = |name: &str| std::env::args().any(|arg| arg == name);
+
+    // `--replay <file>` plays a recorded run back, and `--headless` does it without a window as fast as possible
+    let is_headless = has_flag("--headless");
+    let replay_player = arg_value("--replay").map(|path| ReplayPlayer {
+        replay:  Replay::load(path.as_ref()).expect("Unable to load replay"),
+        next:    0,
+        is_fast: is_headless || has_flag("--fast"),
+    });
+    if is_headless && replay_player.is_none() {
+        println!("--headless needs a replay to play, given with --replay <file>");
+        std::process::exit(1);
+    }
+    // `--seed <n>` reuses a previous run's randomness
+    let random = match &replay_player {
+        Some(player) => RandomStreams::new(player.replay.seed),
+        None => arg_value("--seed")
+            .and_then(|seed| seed.parse().ok())
+            .map_or_else(RandomStreams::default, RandomStreams::new),
+    };
+
+    // `--continue` picks up the variables scripts saved last time, and a replay starts from the ones it was recorded with
+    let lua = LuaResource::default();
+    match &replay_player {
+        Some(player) => lua.global.vars.restore(player.replay.vars.clone()),
+        None if has_flag("--continue") => if let Err(e) = lua.global.vars.load(VARS_PATH.as_ref()) {
+            println!("Unable to load variables from {}: {}", VARS_PATH, e);
+        },
+        None => (),
+    }
+
+    // `--reset-data` clears what an entity kept in `local_entity:data()` when its script is edited, instead of keeping it
+    let reload_settings = ReloadSettings { is_resetting_data: has_flag("--reset-data") };
+
+    // `--real-time <seconds>` keeps turns going at that pace, instead of waiting for the player
+    let turn_mode = arg_value("--real-time")
+        .and_then(|seconds| seconds.parse().ok())
+        .map_or_else(TurnMode::default, TurnMode::real_time);
+
+    let mut app = App::build();
+    if is_headless {
+        // nothing is drawn, so it runs without a window or a GPU. The asset types drawing uses are still added, since
+        // prefabs and levels create them as they load
+        app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs(0)))
+            .add_plugins(MinimalPlugins)
+            .add_plugin(TransformPlugin::default())
+            .add_plugin(InputPlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Texture>()
+            .add_asset::<TextureAtlas>()
+            .add_asset::<ColorMaterial>()
+            .add_asset::<Mesh>()
+            .add_asset::<Font>();
+    } else {
+        app.add_plugins(DefaultPlugins);
+    }
+    match replay_player {
+        Some(player) => app.insert_resource(player),
+        None => app.insert_resource(ReplayRecorder::new(
+            PathBuf::from(REPLAY_PATH),
+            Replay { seed: random.seed, vars: lua.global.vars.to_saved(), ..Replay::default() },
+        )),
+    };
+
+    app.add_plugin(LdtkPlugin)
+        .add_event::<ActionTaken>()
+        .add_event::<GridChanged>()
         .add_asset::<LuaScript>()
         .add_asset::<Prefab>()
         .add_asset::<SpriteInfo>()
         .init_asset_loader::<ItemLoader>()
         .init_asset_loader::<LuaScriptLoader>()
         .init_asset_loader::<PrefabLoader>()
-        .init_resource::<LuaResource>()
+        .insert_resource(lua)
         .init_resource::<TurnCount>()
+        .init_resource::<TurnPhase>()
+        .insert_resource(turn_mode)
+        .insert_resource(reload_settings)
         .insert_resource(MapScale(6.))
+        .insert_resource(History::new(DEFAULT_HISTORY_DEPTH))
+        .insert_resource(random)
         .insert_resource(ControlSettings::default())
+        .init_resource::<LuaModules>()
         .add_startup_system(setup.system())
+        .add_startup_system(spawn_error_overlay.system())
         .add_system(load_level.system())
-        .add_system(spawn_prefab.system())
-        .add_system(update_actions.system())
+        .add_system(update_tile_overlays.system())
+        .add_system(update_error_overlay.system())
+        .add_system(spawn_prefab.system().label("scripts"))
+        .add_system(load_modules.system().before("scripts"))
+        .add_system(reload_scripts.system().label("scripts"))
+        .add_system(update_keyboard_actions.system().with_run_criteria(is_not_replaying.system()))
+        .add_system(update_gamepad_actions.system().with_run_criteria(is_not_replaying.system()))
+        .add_system(update_travel.system().with_run_criteria(is_not_replaying.system()))
         .add_system(update_animations.system())
         .add_system(update_camera.system())
-        .add_system(update_turn.system())
-        .run();
+        .add_system(update_tweens.system())
+        // a whole turn runs in one frame once a player acts, with each phase in its own stage
+        .add_stage_after(CoreStage::Update, TurnPhase::Start, SystemStage::parallel().with_run_criteria(in_turn_start.system()))
+        .add_stage_after(TurnPhase::Start, TurnPhase::PlayerAction, SystemStage::parallel().with_run_criteria(in_player_action.system()))
+        .add_stage_after(TurnPhase::PlayerAction, TurnPhase::NpcActions, SystemStage::parallel().with_run_criteria(in_npc_actions.system()))
+        .add_stage_after(TurnPhase::NpcActions, TurnPhase::Environment, SystemStage::parallel().with_run_criteria(in_environment.system()))
+        .add_stage_after(TurnPhase::Environment, TurnPhase::End, SystemStage::parallel().with_run_criteria(in_turn_end.system()))
+        .add_system_to_stage(TurnPhase::Start, save_replay.system().before("turn_start"))
+        .add_system_to_stage(TurnPhase::Start, check_replay.system().before("turn_start"))
+        .add_system_to_stage(TurnPhase::Start, start_turn.system().label("turn_start").label("scripts"))
+        .add_system_to_stage(TurnPhase::Start, record_history.system().after("apply_world"))
+        .add_system_to_stage(TurnPhase::Start, save_vars.system().with_run_criteria(is_not_replaying.system()).after("turn_start"))
+        .add_system_to_stage(TurnPhase::PlayerAction, record_first_turn.system().before("actions"))
+        .add_system_to_stage(TurnPhase::PlayerAction, update_history.system().with_run_criteria(is_not_replaying.system()).before("actions"))
+        .add_system_to_stage(TurnPhase::PlayerAction, play_replay.system().before("actions"))
+        .add_system_to_stage(TurnPhase::PlayerAction, update_turn_mode.system().with_run_criteria(is_not_replaying.system()).before("actions"))
+        .add_system_to_stage(TurnPhase::PlayerAction, update_actions.system().label("actions").label("scripts"))
+        .add_system_to_stage(TurnPhase::PlayerAction, end_player_action.system().after("actions"))
+        .add_system_to_stage(TurnPhase::PlayerAction, record_replay.system().after("actions"))
+        .add_system_to_stage(TurnPhase::NpcActions, run_npc_actions.system().label("npc_actions").label("scripts"))
+        .add_system_to_stage(TurnPhase::Environment, run_environment.system().label("scripts"))
+        .add_system_to_stage(TurnPhase::End, end_turn.system().label("scripts"));
+
+    // scripts see the world through a view that's synced before they run, and whatever they change is applied after.
+    // Entities despawned by scripts are cleaned up, and messages they queued are delivered, in the next stage that runs
+    app.add_system(refill_entity_pool.system().before("scripts"))
+        .add_system(despawn_marked.system().label("scripts"))
+        .add_system(sync_world_view.system().before("scripts"))
+        .add_system(apply_world_view.system().label("apply_world").after("scripts"));
+    for phase in [TurnPhase::Start, TurnPhase::PlayerAction, TurnPhase::NpcActions, TurnPhase::Environment, TurnPhase::End].iter() {
+        app.add_system_to_stage(phase.clone(), sync_world_view.system().label("sync_world").before("scripts"))
+            .add_system_to_stage(phase.clone(), deliver_messages.system().after("sync_world").before("scripts"))
+            .add_system_to_stage(phase.clone(), despawn_marked.system().label("scripts"))
+            .add_system_to_stage(phase.clone(), apply_world_view.system().label("apply_world").after("scripts"));
+    }
+    app.run();
 }
 
 fn setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     map_scale:    Res<MapScale>,
+    random:       Res<RandomStreams>,
+    mut lua:      ResMut<LuaResource>,
+    mut modules:  ResMut<LuaModules>,
 ) {
+    println!("Random seed: {}", random.seed);
+    lua.global.random = random.clone();
+    lua.sync();
+
     // Enable hot reload
     asset_server.watch_for_changes().unwrap();
+    match asset_server.load_folder(MODULE_DIR) {
+        Ok(handles) => modules.handles = handles,
+        Err(e)      => println!("Unable to load Lua modules from {}: {:?}", MODULE_DIR, e),
+    }
     //asset_server.load_folder(".").expect("Error loading assets folder");
 
     commands
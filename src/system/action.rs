@@ -1,75 +1,295 @@
 use bevy::{
     prelude::*,
 };
+use enumset::*;
 use std::collections::HashMap;
 use std::time::Duration;
 
 use crate::data::action::*;
+use crate::data::faction::*;
 use crate::data::level::*;
 use crate::data::player::*;
+use crate::data::resolve::*;
+use crate::data::travel::*;
 use crate::data::turn::*;
+use crate::data::tween::*;
 use crate::lua::*;
+use crate::system::player::*;
 
-pub fn update_actions(
+pub fn update_keyboard_actions(
     time:           Res<Time>,
     controls:       Res<ControlSettings>,
     keyboard_input: Res<Input<KeyCode>>,
-    mut lua:        ResMut<LuaResource>,
-    mut turn_count: ResMut<TurnCount>,
-    mut query_set:  QuerySet<(
-        Query<(Entity, &mut Pos, &mut LocalActions, &OwningLevel)>,
+    query:          Query<(&ActionSource, &mut LocalActions)>,
+) {
+    let timestamp = time.seconds_since_startup();
+    query.for_each_mut(|(source, mut actions)| {
+        if *source != ActionSource::Keyboard {
+            return;
+        }
+        actions.update_held(timestamp, HeldInputs {
+            north: keyboard_input.pressed(controls.north),
+            south: keyboard_input.pressed(controls.south),
+            east:  keyboard_input.pressed(controls.east),
+            west:  keyboard_input.pressed(controls.west),
+            run:   keyboard_input.pressed(controls.run),
+            wait:  keyboard_input.pressed(controls.wait),
+            rest:  keyboard_input.pressed(controls.rest),
+        });
+        for (digit, key) in COUNT_KEYS.iter().enumerate() {
+            if keyboard_input.just_pressed(key.clone()) {
+                actions.push_count_digit(digit);
+            }
+        }
+        if keyboard_input.just_pressed(controls.cancel) {
+            actions.cancel();
+        }
+        if keyboard_input.just_pressed(controls.rest) {
+            actions.start_rest(timestamp);
+        }
+    });
+}
+
+pub fn update_gamepad_actions(
+    time:     Res<Time>,
+    controls: Res<ControlSettings>,
+    buttons:  Res<Input<GamepadButton>>,
+    axes:     Res<Axis<GamepadAxis>>,
+    query:    Query<(&ActionSource, &mut LocalActions)>,
+) {
+    let timestamp = time.seconds_since_startup();
+    query.for_each_mut(|(source, mut actions)| {
+        let gamepad = match source {
+            ActionSource::Gamepad(idx) => Gamepad(idx.clone()),
+            _ => return,
+        };
+        let button = |button_type| GamepadButton(gamepad, button_type);
+        let stick_x = axes.get(GamepadAxis(gamepad, GamepadAxisType::LeftStickX)).unwrap_or(0.);
+        let stick_y = axes.get(GamepadAxis(gamepad, GamepadAxisType::LeftStickY)).unwrap_or(0.);
+        actions.update_held(timestamp, HeldInputs {
+            north: buttons.pressed(button(GamepadButtonType::DPadUp))    || stick_y >  STICK_DEADZONE,
+            south: buttons.pressed(button(GamepadButtonType::DPadDown))  || stick_y < -STICK_DEADZONE,
+            east:  buttons.pressed(button(GamepadButtonType::DPadRight)) || stick_x >  STICK_DEADZONE,
+            west:  buttons.pressed(button(GamepadButtonType::DPadLeft))  || stick_x < -STICK_DEADZONE,
+            run:   buttons.pressed(button(controls.pad_run)),
+            wait:  buttons.pressed(button(controls.pad_wait)),
+            rest:  buttons.pressed(button(controls.pad_rest)),
+        });
+        if buttons.just_pressed(button(controls.pad_cancel)) {
+            actions.cancel();
+        }
+        if buttons.just_pressed(button(controls.pad_rest)) {
+            actions.start_rest(timestamp);
+        }
+    });
+}
+
+pub fn update_actions(
+    mut commands:      Commands,
+    time:              Res<Time>,
+    map_scale:         Res<MapScale>,
+    mut lua:           ResMut<LuaResource>,
+    mut actions_taken: EventWriter<ActionTaken>,
+    event_handlers:    Query<&EnumSet<EntityEvent>>,
+    players:           Query<(), With<Player>>,
+    mut query_set:     QuerySet<(
+        Query<(Entity, &mut Pos, &mut LocalActions, &OwningLevel, Option<&EnumSet<Movement>>, Option<&Tween>, Option<&Energy>, Option<&Faction>, Option<&MovePriority>)>,
         Query<(Entity, &mut Grid)>,
         Query<(&Player, &Pos)>,
+        Query<(Entity, &Pos, &OwningLevel, &Faction)>,
     )>,
 ) {
     let timestamp = time.seconds_since_startup();
-    let mut move_reqs: HashMap<Entity, HashMap<Entity, (Pos, Dir)>> = HashMap::new();
+    let interrupted = lua.global.take_interrupt();
+    let mut action_reqs: HashMap<Entity, HashMap<Entity, ActionReq>> = HashMap::new();
 
-    query_set.q0_mut().for_each_mut(|(entity, pos, mut actions, OwningLevel(level_entity))| {
-        actions.north.update(timestamp, keyboard_input.pressed(controls.north));
-        actions.south.update(timestamp, keyboard_input.pressed(controls.south));
-        actions.east .update(timestamp, keyboard_input.pressed(controls.east));
-        actions.west .update(timestamp, keyboard_input.pressed(controls.west));
-        actions.run  .update(timestamp, keyboard_input.pressed(controls.run));
-        if actions.run.value {
+    let mut hostiles: HashMap<Entity, Vec<(Entity, Pos)>> = HashMap::new();
+    query_set.q3().for_each(|(entity, pos, OwningLevel(level_entity), faction)| {
+        if *faction == Faction::Hostile {
+            hostiles.entry(level_entity.clone()).or_insert_with(|| Vec::new()).push((entity, pos.clone()));
+        }
+    });
+
+    query_set.q0_mut().for_each_mut(|(entity, pos, mut actions, OwningLevel(level_entity), movement, _, energy, faction, priority)| {
+        if actions.run.value || actions.travel.is_some() {
             actions.move_timer.set_duration(Duration::from_secs_f32(SECONDS_TO_RUN));
         } else {
             actions.move_timer.set_duration(Duration::from_secs_f32(SECONDS_TO_WALK));
         }
 
         actions.move_timer.tick(time.delta());
-        if let Some(dir) = actions.dir(timestamp) {
-            if actions.move_timer.finished() {
-                move_reqs.entry(level_entity.clone())
+        // actors wait for the scheduler to give them enough energy before acting again, and queued actions don't wait for the timer
+        if (actions.move_timer.finished() || actions.queued.is_some()) && energy.map_or(true, |e| e.can_act()) {
+            if let Some((action, origin)) = actions.next_action(&pos, timestamp) {
+                action_reqs.entry(level_entity.clone())
                     .or_insert_with(|| HashMap::new())
-                    .insert(entity, (pos.clone(), dir));
+                    .insert(entity, ActionReq {
+                        pos:      pos.clone(),
+                        action,
+                        origin,
+                        repeat:   actions.repeat.clone(),
+                        travel:   actions.travel.clone(),
+                        movement: movement.cloned().unwrap_or_default(),
+                        faction:  faction.cloned().unwrap_or_default(),
+                        priority: priority.cloned().unwrap_or_default(),
+                    });
             }
         }
     });
     let mut move_approves = HashMap::new();
+    let mut move_denies   = HashMap::new();
+    let mut waits         = Vec::new();
+    let mut interrupts    = Vec::new();
+    let mut repeat_seen   = HashMap::new();
+    let mut travel_seen   = HashMap::new();
+    let mut denied        = Vec::new();
     query_set.q1_mut().for_each_mut(|(level_entity, mut grid)| {
-        if let Some(entities) = move_reqs.get(&level_entity) {
-            for (entity, (prev_pos, dir)) in entities {
-                let target_pos = prev_pos.step(dir.clone());
-                // println!("prev_pos {:?} target_pos {:?} prev_state {:?} grid_state {:?} is_blocking {:?}", prev_pos, target_pos, grid.get(prev_pos), grid.get(&target_pos), grid.get(&target_pos).is_blocking());
-                if !grid.get(&target_pos).is_blocking() {
-                    grid.set(prev_pos, PosState::None);
-                    grid.set(&target_pos, PosState::Entity(entity.clone()));
-                    move_approves.insert(entity.clone(), target_pos);
-                    // player has moved, so we increment the turn count
-                    turn_count.0 += 1;
-                    lua.global.turn_count += 1;
-                    lua.sync();
+        if let Some(entities) = action_reqs.get(&level_entity) {
+            let visible_hostiles = hostiles.get(&level_entity).map(|v| v.as_slice()).unwrap_or(&[]);
+            let mut moves = Vec::new();
+            for (entity, req) in entities {
+                let prev_pos = &req.pos;
+                // repeated actions and travel stop as soon as a script asks
+                if req.origin != ActionOrigin::Input && interrupted {
+                    interrupts.push(entity.clone());
+                    continue;
+                }
+                if let (ActionOrigin::Repeat, Some(repeat)) = (req.origin, &req.repeat) {
+                    let visible: Vec<Entity> = visible_hostiles.iter()
+                        .filter(|(h, pos)| h != entity && grid.can_see(prev_pos, pos))
+                        .map(|(h, _)| h.clone())
+                        .collect();
+                    match &repeat.seen {
+                        Some(seen) if visible.iter().any(|h| !seen.contains(h)) => {
+                            interrupts.push(entity.clone());
+                            continue;
+                        },
+                        Some(_) => (),
+                        None    => { repeat_seen.insert(entity.clone(), visible); },
+                    }
+                }
+                // travel only stops for what's new since it started, hostiles included, so running past one already in view still works
+                if let Some(travel) = &req.travel {
+                    let visible: Vec<Entity> = grid.visible_occupants(prev_pos).into_iter().filter(|e| e != entity).collect();
+                    match &travel.seen {
+                        Some(seen) if visible.iter().any(|e| !seen.contains(e)) => {
+                            interrupts.push(entity.clone());
+                            continue;
+                        },
+                        Some(_) => (),
+                        None    => { travel_seen.insert(entity.clone(), visible); },
+                    }
+                }
+                match req.action {
+                    Action::Move(dir) => moves.push(MoveReq {
+                        entity:   entity.clone(),
+                        from:     prev_pos.clone(),
+                        dir,
+                        movement: req.movement.clone(),
+                        faction:  req.faction.clone(),
+                        priority: req.priority.clone(),
+                    }),
+                    Action::Wait => {
+                        waits.push(entity.clone());
+                        actions_taken.send(ActionTaken { entity: entity.clone(), action: req.action, cost: ACTION_COST });
+                    },
+                }
+            }
+
+            // everyone on the level moves at once, so who gets where doesn't depend on query order
+            let resolution = grid.resolve_moves(moves);
+            for (entity, target_pos) in resolution.approved {
+                let req = &entities[&entity];
+                move_approves.insert(entity, target_pos);
+                actions_taken.send(ActionTaken { entity, action: req.action, cost: ACTION_COST });
+
+                // runs stop where the corridor changes shape, or next to anything worth a look
+                if let (Some(travel), Action::Move(dir)) = (&req.travel, req.action) {
+                    if travel.is_run() {
+                        let is_next_to_entity = Dir::ALL.iter().any(|d| grid.occupants(&target_pos.step(d.clone())).iter().any(|o| o.entity != entity));
+                        if is_next_to_entity || grid.is_branching(&req.pos, &target_pos, dir, req.movement.clone()) {
+                            interrupts.push(entity);
+                        }
+                    }
                 }
             }
+            for (move_req, denial) in resolution.denied {
+                if entities[&move_req.entity].origin == ActionOrigin::Travel {
+                    interrupts.push(move_req.entity);
+                } else {
+                    move_denies.insert(move_req.entity, move_req.dir);
+                }
+                denied.push((move_req, denial));
+            }
         }
     });
-    query_set.q0_mut().for_each_mut(|(entity, mut pos, mut actions, _)| {
+
+    // let the losers' scripts know what happened
+    for (move_req, denial) in denied {
+        if event_handlers.get(move_req.entity).map_or(false, |h| h.contains(EntityEvent::OnMoveDenied)) {
+            let args = vec![
+                StoredValue::String(move_req.dir.name().to_string()).into(),
+                StoredValue::String(denial.reason().to_string()).into(),
+                denial.other().map_or(StoredValue::Nil.into(), EventArg::Entity),
+            ];
+            if let Err(e) = lua.run_entity_event(EntityEvent::OnMoveDenied, LuaEntity::with_args(move_req.entity, args)) {
+                lua.report_error(&format!("on_move_denied for {:?}", move_req.entity), &e);
+            }
+        }
+    }
+    let mut player_moves = Vec::new();
+    query_set.q0_mut().for_each_mut(|(entity, mut pos, mut actions, _, _, tween, _, _, _)| {
+        if let (Some(repeat), Some(seen)) = (actions.repeat.as_mut(), repeat_seen.remove(&entity)) {
+            repeat.seen = Some(seen);
+        }
+        // start from wherever the sprite currently is, so chained moves don't snap back to the tile
+        let from = match tween {
+            Some(tween) if tween.is_playing() => tween.ground_translation(),
+            _ => pos.translation(&map_scale),
+        };
         if let Some(new_pos) = move_approves.get(&entity) {
+            if players.get(entity).is_ok() {
+                player_moves.push((entity, pos.clone(), new_pos.clone()));
+            }
             actions.move_timer.reset();
             pos.x = new_pos.x;
             pos.y = new_pos.y;
             pos.z = new_pos.z;
+            let seconds = actions.move_timer.duration().as_secs_f32();
+            commands.entity(entity).insert(Tween::hop(from, new_pos.translation(&map_scale), seconds, &map_scale));
+
+            if let Some(travel) = actions.travel.as_mut() {
+                if let Some(seen) = travel_seen.remove(&entity) {
+                    travel.seen = Some(seen);
+                }
+                if !travel.advance() {
+                    actions.travel = None;
+                }
+            }
+        } else if let Some(dir) = move_denies.get(&entity) {
+            actions.move_timer.reset();
+            actions.cancel();
+            commands.entity(entity).insert(Tween::bump(from, dir.clone(), &map_scale));
+        } else if waits.contains(&entity) {
+            actions.move_timer.reset();
+        }
+        if interrupts.contains(&entity) {
+            actions.cancel();
         }
     });
-}
\ No newline at end of file
+    player_moves.sort_by_key(|(entity, _, _)| entity.id());
+    for (entity, from, to) in player_moves {
+        notify_player_moved(&mut lua, entity, from, to);
+    }
+}
+
+struct ActionReq {
+    pos:      Pos,
+    action:   Action,
+    origin:   ActionOrigin,
+    repeat:   Option<Repeat>,
+    travel:   Option<Travel>,
+    movement: EnumSet<Movement>,
+    faction:  Faction,
+    priority: MovePriority,
+}
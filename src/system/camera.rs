@@ -1,34 +1,42 @@
 use bevy::{
     prelude::*,
-    render::camera::Camera,
+    render::{camera::Camera, render_graph::base},
 };
 
 use crate::data::level::*;
 use crate::data::player::*;
-use crate::data::sprite::TILE_SIZE;
+use crate::data::tween::*;
 
 pub fn update_camera(
     map_scale:     Res<MapScale>,
     mut query_set: QuerySet<(
-        Query<(&Transform, &Player)>,
+        Query<(&Pos, Option<&Tween>, &Player)>,
         Query<(&mut Transform, &Camera)>,
-        Query<(&mut Transform, &Pos)>,
+        Query<(&mut Transform, &Pos, Option<&Tween>)>,
     )>,
 ) {
-    query_set.q2_mut().for_each_mut(|(mut transform, pos)| {
-        transform.translation.x = map_scale.0 * (TILE_SIZE *  pos.x as f32 + 0.5 * TILE_SIZE);
-        transform.translation.y = map_scale.0 * (TILE_SIZE * -pos.y as f32 - 0.5 * TILE_SIZE);
-        transform.translation.z = pos.z as f32;
+    query_set.q2_mut().for_each_mut(|(mut transform, pos, tween)| {
+        transform.translation = match tween {
+            Some(tween) if tween.is_playing() => tween.translation(),
+            _ => pos.translation(&map_scale),
+        };
     });
 
+    // follow the player's interpolated path, ignoring hops and bumps so the view doesn't shake
     let mut player_trans = None;
-    query_set.q0().for_each(|(transform, _)| {
-        player_trans = Some(transform.clone());
+    query_set.q0().for_each(|(pos, tween, _)| {
+        player_trans = Some(match tween {
+            Some(tween) if tween.is_playing() => tween.ground_translation(),
+            _ => pos.translation(&map_scale),
+        });
     });
 
     if let Some(t) = player_trans {
-        query_set.q1_mut().for_each_mut(|(mut transform, _)| {
-            transform.translation = t.translation;
+        // only the map's camera, the UI one stays put
+        query_set.q1_mut().for_each_mut(|(mut transform, camera)| {
+            if camera.name.as_deref() == Some(base::camera::CAMERA_2D) {
+                transform.translation = t;
+            }
         })
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,194 @@
+use bevy::{
+    prelude::*,
+};
+
+use crate::data::action::*;
+use crate::data::history::*;
+use crate::data::level::*;
+use crate::data::prefab::*;
+use crate::data::random::*;
+use crate::data::replay::*;
+use crate::data::sprite::*;
+use crate::data::turn::*;
+use crate::data::tween::*;
+use crate::lua::*;
+
+/// Saves the world once a turn has started, so it can be rewound to this point. The first turn starts before the level
+/// has loaded, so `record_first_turn` saves that one instead
+pub fn record_history(
+    mut history: ResMut<History>,
+    mut lua:     ResMut<LuaResource>,
+    turn_count:  Res<TurnCount>,
+    random:      Res<RandomStreams>,
+    grids:       Query<(Entity, &Grid)>,
+    positions:   Query<(Entity, &Pos)>,
+    anims:       Query<(Entity, &AnimState)>,
+    energies:    Query<(Entity, &Energy)>,
+) {
+    if grids.iter().next().is_none() {
+        return;
+    }
+    history.push(take_snapshot(&mut lua, &turn_count, &random, &grids, &positions, &anims, &energies));
+}
+
+/// Saves the first turn once the level and everything placed in it has spawned, as long as no turn has been saved yet
+pub fn record_first_turn(
+    mut history: ResMut<History>,
+    mut lua:     ResMut<LuaResource>,
+    turn_count:  Res<TurnCount>,
+    random:      Res<RandomStreams>,
+    grids:       Query<(Entity, &Grid)>,
+    positions:   Query<(Entity, &Pos)>,
+    anims:       Query<(Entity, &AnimState)>,
+    energies:    Query<(Entity, &Energy)>,
+    to_spawn:    Query<(), With<PrefabToSpawn>>,
+) {
+    if !history.is_empty() || grids.iter().next().is_none() || to_spawn.iter().next().is_some() {
+        return;
+    }
+    history.push(take_snapshot(&mut lua, &turn_count, &random, &grids, &positions, &anims, &energies));
+}
+
+fn take_snapshot(
+    lua:        &mut LuaResource,
+    turn_count: &TurnCount,
+    random:     &RandomStreams,
+    grids:      &Query<(Entity, &Grid)>,
+    positions:  &Query<(Entity, &Pos)>,
+    anims:      &Query<(Entity, &AnimState)>,
+    energies:   &Query<(Entity, &Energy)>,
+) -> Snapshot {
+    let lua_snapshot = match lua.take_snapshot() {
+        Ok(snapshot) => snapshot,
+        Err(e)       => {
+            println!("Failed to snapshot Lua state: {}", e);
+            LuaSnapshot::default()
+        },
+    };
+    Snapshot {
+        turn_count: turn_count.0,
+        grids:      grids.iter().map(|(entity, grid)| (entity, grid.clone())).collect(),
+        positions:  positions.iter().map(|(entity, pos)| (entity, pos.clone())).collect(),
+        anims:      anims.iter().map(|(entity, anim)| (entity, anim.clone())).collect(),
+        energies:   energies.iter().map(|(entity, energy)| (entity, energy.clone())).collect(),
+        random:     random.save(),
+        lua:        lua_snapshot,
+    }
+}
+
+pub fn update_history(
+    mut commands:    Commands,
+    mut history:     ResMut<History>,
+    mut lua:         ResMut<LuaResource>,
+    mut turn_count:  ResMut<TurnCount>,
+    recorder:        Option<ResMut<ReplayRecorder>>,
+    random:          Res<RandomStreams>,
+    controls:        Res<ControlSettings>,
+    keyboard_input:  Res<Input<KeyCode>>,
+    buttons:         Res<Input<GamepadButton>>,
+    mut grid_events: EventWriter<GridChanged>,
+    sources:         Query<&ActionSource>,
+    mut query_set:   QuerySet<(
+        Query<(Entity, &mut Grid)>,
+        Query<(Entity, &mut Pos, Option<&Tween>)>,
+        Query<(Entity, &mut AnimState)>,
+        Query<(Entity, &mut Energy)>,
+        Query<&mut LocalActions>,
+    )>,
+) {
+    let pad_pressed = |button_type| sources.iter().any(|source| match source {
+        ActionSource::Gamepad(idx) => buttons.just_pressed(GamepadButton(Gamepad(idx.clone()), button_type)),
+        _ => false,
+    });
+    let snapshot = if keyboard_input.just_pressed(controls.undo) || pad_pressed(controls.pad_undo) {
+        history.undo()
+    } else if keyboard_input.just_pressed(controls.redo) || pad_pressed(controls.pad_redo) {
+        history.redo()
+    } else {
+        None
+    };
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None           => return,
+    };
+
+    turn_count.0 = snapshot.turn_count;
+    random.restore(&snapshot.random);
+    if let Some(mut recorder) = recorder {
+        recorder.seek(snapshot.turn_count);
+    }
+    lua.global.turn_count = snapshot.turn_count;
+    lua.sync();
+    if let Err(e) = lua.restore_snapshot(&snapshot.lua) {
+        println!("Failed to restore Lua state: {}", e);
+    }
+    query_set.q0_mut().for_each_mut(|(entity, mut grid)| {
+        if let Some(saved) = snapshot.grids.get(&entity) {
+            // tiles scripts changed need their overlays put back too
+            for (pos, state) in grid.changed_tiles(saved) {
+                grid_events.send(GridChanged { level: entity, pos, state });
+            }
+            *grid = saved.clone();
+        }
+    });
+    query_set.q1_mut().for_each_mut(|(entity, mut pos, tween)| {
+        if let Some(saved) = snapshot.positions.get(&entity) {
+            *pos = saved.clone();
+        }
+        // snap straight to the restored tile rather than sliding back
+        if tween.is_some() {
+            commands.entity(entity).remove::<Tween>();
+        }
+    });
+    query_set.q2_mut().for_each_mut(|(entity, mut anim)| {
+        if let Some(saved) = snapshot.anims.get(&entity) {
+            *anim = saved.clone();
+        }
+    });
+    query_set.q3_mut().for_each_mut(|(entity, mut energy)| {
+        if let Some(saved) = snapshot.energies.get(&entity) {
+            *energy = saved.clone();
+        }
+    });
+    query_set.q4_mut().for_each_mut(|mut actions| actions.cancel());
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::app::Events;
+
+    use super::*;
+
+    #[test]
+    fn undo_after_one_turn_puts_the_player_back() {
+        let mut world = World::default();
+        world.insert_resource(History::new(DEFAULT_HISTORY_DEPTH));
+        world.insert_resource(LuaResource::default());
+        world.insert_resource(TurnCount::default());
+        world.insert_resource(RandomStreams::new(1));
+        world.insert_resource(ControlSettings::default());
+        world.insert_resource(Input::<KeyCode>::default());
+        world.insert_resource(Input::<GamepadButton>::default());
+        world.insert_resource(Events::<GridChanged>::default());
+        let mut record     = SystemStage::single(record_history.system());
+        let mut first_turn = SystemStage::single(record_first_turn.system());
+        let mut undo       = SystemStage::single(update_history.system());
+
+        // the first turn starts before the level has loaded
+        record.run(&mut world);
+        world.spawn().insert(Grid::new(4, 4, 1));
+        let player = world.spawn().insert(Pos { x: 1, y: 1, z: 0 }).id();
+        first_turn.run(&mut world);
+
+        // the player moves and the second turn starts
+        world.get_mut::<Pos>(player).unwrap().x = 2;
+        world.get_resource_mut::<TurnCount>().unwrap().0 = 1;
+        record.run(&mut world);
+
+        let undo_key = world.get_resource::<ControlSettings>().unwrap().undo;
+        world.get_resource_mut::<Input<KeyCode>>().unwrap().press(undo_key);
+        undo.run(&mut world);
+        assert_eq!(*world.get::<Pos>(player).unwrap(), Pos { x: 1, y: 1, z: 0 });
+        assert_eq!(world.get_resource::<TurnCount>().unwrap().0, 0);
+    }
+}
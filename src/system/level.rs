@@ -1,13 +1,16 @@
 use bevy::prelude::*;
 use bevy_ldtk::*;
+use enumset::*;
 use ldtk::*;
 use serde_json::value::Value;
 use std::collections::HashMap;
 
 use crate::data::action::*;
+use crate::data::color::*;
 use crate::data::level::*;
 use crate::data::player::Player;
 use crate::data::prefab::*;
+use crate::data::sprite::TILE_SIZE;
 use crate::lua::*;
 
 pub fn load_level(
@@ -21,15 +24,24 @@ pub fn load_level(
     )>, 
 ) {
     query_set.q0().for_each(|(layer_entity, ldtk_handle, LevelToLoad(level_idx))| {
+        let mut player_count = 0;
         if let Some(ltdk_map) = map_assets.get(ldtk_handle) {
-            let mut level_info = LevelInfo {level_idx: level_idx.clone(), ..LevelInfo::default()};
             let level = &ltdk_map.project.levels[level_idx.clone()];
+            let bg_color = level.__bg_color.parse::<css_color_parser::Color>()
+                .map(|c| Color::rgba_u8(c.r, c.g, c.b, (c.a * 255.) as u8))
+                .unwrap_or(Color::BLACK);
+            let mut level_info = LevelInfo {level_idx: level_idx.clone(), bg_color, ..LevelInfo::default()};
             let entity_z = level.layer_instances.as_ref().unwrap().len() as f32 + 1.;
             let mut grid = create_grid(level);
 
             for field in level.field_instances.iter() {
                 match field.__identifier.as_str() {
-                    "embedded_script" => lua.exec_script(field.__value.as_str().expect("embedded_script was not a String")),
+                    "embedded_script" => {
+                        let name = format!("{}#embedded_script", level.identifier);
+                        if let Err(e) = lua.exec_script(&name, field.__value.as_str().expect("embedded_script was not a String")) {
+                            lua.report_error(&name, &e);
+                        }
+                    },
                     "subtitle"        => level_info.subtitle = field.__value.as_str().map(|s| s.to_string()),
                     "title"           => level_info.title    = field.__value.as_str().expect("title was not a String").to_string(),
                     e => println!("Unhandled level field `{:?}`", e),
@@ -45,10 +57,14 @@ pub fn load_level(
                             "Player_spawn" => {
                                 let pos = grid_pos(layer, layer_z, entity);
                                 println!("player pos {:?}", pos);
+                                // the first player uses the keyboard, and any others get a gamepad each
+                                let source = if player_count == 0 { ActionSource::Keyboard } else { ActionSource::Gamepad(player_count - 1) };
+                                player_count += 1;
                                 // change later
                                 let entity = commands.spawn()
                                     .insert(Player)
                                     .insert(LocalActions::default())
+                                    .insert(source)
                                     .insert(OwningLevel(layer_entity))
                                     .insert(PrefabToSpawn {
                                         prefab: asset_server.load("actors/player.prefab.ron"),
@@ -56,7 +72,7 @@ pub fn load_level(
                                     })
                                     .insert(pos)
                                     .id();
-                                grid.set(&pos, PosState::Entity(entity));
+                                grid.add_occupant(&pos, entity, EnumSet::empty());
                             },
                             "Prefab" => {
                                 let mut prefab_file = None;
@@ -76,7 +92,7 @@ pub fn load_level(
                                     })
                                     .insert(pos)
                                     .id();
-                                grid.set(&pos, PosState::Entity(entity));
+                                grid.add_occupant(&pos, entity, EnumSet::empty());
                             },
                             s => panic!("Unknown entity identifier {}", s),
                         }
@@ -98,6 +114,12 @@ pub fn load_level(
                         }
                     }
                 });
+            let event = GlobalEvent::new("level_loaded")
+                .with_value("index", level_info.level_idx as i64)
+                .with_value("title", level_info.title.as_str());
+            if let Err(e) = lua.run_global_event(event) {
+                lua.report_error("global level_loaded", &e);
+            }
             commands.entity(layer_entity)
                 .insert(level_info)
                 .insert(grid)
@@ -106,6 +128,38 @@ pub fn load_level(
     });
 }
 
+/// Covers tiles changed by scripts, since the map's own tiles are drawn once when the level loads
+pub fn update_tile_overlays(
+    mut commands:    Commands,
+    mut overlays:    Local<HashMap<(Entity, Pos), Entity>>,
+    mut materials:   ResMut<Assets<ColorMaterial>>,
+    map_scale:       Res<MapScale>,
+    mut grid_events: EventReader<GridChanged>,
+    levels:          Query<&LevelInfo>,
+) {
+    for GridChanged { level, pos, state } in grid_events.iter() {
+        let color = match state {
+            PosState::None        => levels.get(level.clone()).map_or(Color::BLACK, |info| info.bg_color),
+            PosState::Solid       => Palette::SaltBox.color(),
+            PosState::Floorless   => Palette::Shark.color(),
+            PosState::Water       => Palette::RegentStBlue.color(),
+            PosState::Damaging(_) => Palette::Geraldine.color(),
+        };
+        if let Some(overlay) = overlays.remove(&(level.clone(), pos.clone())) {
+            commands.entity(overlay).despawn();
+        }
+        // just above the map's tiles, and below travel markers and entities
+        let translation = pos.translation(&map_scale) + Vec3::new(0., 0., 0.25);
+        let overlay = commands.spawn_bundle(SpriteBundle {
+            material:  materials.add(color.into()),
+            sprite:    Sprite::new(Vec2::splat(TILE_SIZE * map_scale.0)),
+            transform: Transform::from_translation(translation),
+            ..Default::default()
+        }).id();
+        overlays.insert((level.clone(), pos.clone()), overlay);
+    }
+}
+
 fn get_dim(level: &Level) -> Result<(usize, usize), String> {
     if let Some(layers) = level.layer_instances.as_ref() {
         if !layers.is_empty() {
@@ -1,6 +1,13 @@
 pub mod action;
 pub mod camera;
+pub mod history;
 pub mod level;
+pub mod overlay;
+pub mod player;
 pub mod prefab;
+pub mod replay;
 pub mod sprite;
-pub mod turn;
\ No newline at end of file
+pub mod travel;
+pub mod turn;
+pub mod tween;
+pub mod world;
\ No newline at end of file
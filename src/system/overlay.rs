@@ -0,0 +1,74 @@
+use bevy::prelude::*;
+
+use crate::data::action::*;
+use crate::data::color::*;
+use crate::lua::script::*;
+
+pub const ERROR_FONT_PATH: &'static str = "fonts/OpenDyslexic-Regular.otf";
+pub const MAX_SHOWN_ERRORS: usize = 5;
+
+/// Text listing the latest script errors, hidden until one happens
+pub struct ErrorOverlay;
+
+pub fn spawn_error_overlay(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+) {
+    commands.spawn_bundle(UiCameraBundle::default());
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect { left: Val::Px(8.), bottom: Val::Px(8.), ..Default::default() },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle { font: asset_server.load(ERROR_FONT_PATH), font_size: 18., color: Palette::Geraldine.color() },
+                TextAlignment::default(),
+            ),
+            visible: Visible { is_visible: false, is_transparent: true },
+            ..Default::default()
+        })
+        .insert(ErrorOverlay);
+}
+
+/// Shows script errors as they're reported, until dismissed; the game keeps running either way
+pub fn update_error_overlay(
+    mut lua:        ResMut<LuaResource>,
+    mut shown:      Local<Vec<String>>,
+    controls:       Res<ControlSettings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    buttons:        Res<Input<GamepadButton>>,
+    sources:        Query<&ActionSource>,
+    mut query:      Query<(&mut Text, &mut Visible), With<ErrorOverlay>>,
+) {
+    let pad_pressed = sources.iter().any(|source| match source {
+        ActionSource::Gamepad(idx) => buttons.just_pressed(GamepadButton(Gamepad(idx.clone()), controls.pad_dismiss)),
+        _ => false,
+    });
+    let is_dismissed = !shown.is_empty() && (keyboard_input.just_pressed(controls.dismiss) || pad_pressed);
+    let errors = lua.take_errors();
+    if errors.is_empty() && !is_dismissed {
+        return;
+    }
+    if is_dismissed {
+        shown.clear();
+    }
+    for error in errors {
+        shown.push(format!("Error in {}: {}", error.context, error.message));
+    }
+    let excess = shown.len().saturating_sub(MAX_SHOWN_ERRORS);
+    shown.drain(..excess);
+
+    query.for_each_mut(|(mut text, mut visible)| {
+        visible.is_visible = !shown.is_empty();
+        if let Some(section) = text.sections.first_mut() {
+            section.value = if shown.is_empty() {
+                String::new()
+            } else {
+                format!("{}\n(press {:?} to dismiss)", shown.join("\n"), controls.dismiss)
+            };
+        }
+    });
+}
@@ -0,0 +1,13 @@
+use bevy::prelude::*;
+
+use crate::data::level::*;
+use crate::lua::*;
+
+/// Lets scripts know a player moved, through the `player_moved` global event with the player and its old and new
+/// positions. Only called where players actually move, so restoring an earlier turn doesn't count
+pub fn notify_player_moved(lua: &mut LuaResource, entity: Entity, from: Pos, to: Pos) {
+    let event = GlobalEvent::new("player_moved").with_entity("player", entity).with_value("from", from).with_value("to", to);
+    if let Err(e) = lua.run_global_event(event) {
+        lua.report_error(&format!("global player_moved for {:?}", entity), &e);
+    }
+}
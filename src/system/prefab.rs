@@ -1,10 +1,16 @@
-use bevy::prelude::*;
+use bevy::{
+    asset::LoadState,
+    prelude::*,
+};
+use enumset::*;
 
+use crate::data::action::*;
 use crate::data::level::*;
-use crate::lua::{script::*, entity::*};
+use crate::lua::{script::*, entity::*, global::*};
 use crate::data::prefab::*;
 use crate::data::sprite::SpriteInfo;
 use crate::data::sprite::TILE_SIZE;
+use crate::data::turn::*;
 
 pub fn spawn_prefab(
     mut commands: Commands,
@@ -13,9 +19,15 @@ pub fn spawn_prefab(
     prefabs:      Res<Assets<Prefab>>,
     scripts:      Res<Assets<LuaScript>>,
     sprites:      Res<Assets<SpriteInfo>>,
-    query:        Query<(Entity, &PrefabToSpawn)>,
+    mut grids:    Query<&mut Grid>,
+    modules:      Res<LuaModules>,
+    query:        Query<(Entity, &PrefabToSpawn, Option<&Pos>, Option<&OwningLevel>)>,
 ) {
-    query.for_each(|(entity, pref_to_spawn)| {
+    // scripts may require the shared libraries as soon as they run
+    if !modules.is_loaded {
+        return;
+    }
+    query.for_each(|(entity, pref_to_spawn, pos, owning_level)| {
         if let Some(prefab) = prefabs.get(&pref_to_spawn.prefab) {
             if let Some(sprite) = sprites.get(&prefab.sprite) {
                 //let scale = sprite.scale * 1.5 / map_scale.0;  // why 1.5? it's a mystery!
@@ -37,7 +49,27 @@ pub fn spawn_prefab(
                             ..Default::default()
                         },
                         ..Default::default()
-                    });
+                    })
+                    .insert(prefab.movement)
+                    .insert(prefab.faction)
+                    .insert(prefab.priority);
+
+                if let (Some(pos), Some(OwningLevel(level_entity))) = (pos, owning_level) {
+                    if let Ok(mut grid) = grids.get_mut(level_entity.clone()) {
+                        grid.set_movement(pos, entity, prefab.movement);
+                    }
+                }
+
+                if let Some(speed) = prefab.speed {
+                    commands.entity(entity).insert(Energy::new(speed));
+                }
+
+                if let Some(source) = prefab.source {
+                    commands.entity(entity).insert(source);
+                    if !source.is_scripted() {
+                        commands.entity(entity).insert(LocalActions::default());
+                    }
+                }
 
                 if let Some(anim_state) = sprite.anim.default_anim_state() {
                     commands.entity(entity)
@@ -46,16 +78,151 @@ pub fn spawn_prefab(
                 }
 
                 if let Some(script_handle) = &prefab.script {
+                    // kept so the script can be run again if it changes
+                    commands.entity(entity).insert(script_handle.clone());
                     let script = scripts.get(script_handle).expect("script dependency not loaded when prefab is spawned");
-                    let run_results = lua.exec_script_with_instance(script, LuaEntity::new(entity)).unwrap();
-                    if run_results.events_registered.contains(EntityEvent::OnInit) {
-                        lua.run_event(EntityEvent::OnInit, run_results).unwrap()
-                            .update_entity(&mut commands);
-                    } else {
-                        run_results.update_entity(&mut commands);
+                    match lua.exec_script_with_instance(script, LuaEntity::for_script(entity)) {
+                        Ok(run_results) if run_results.events_registered.contains(EntityEvent::OnInit) => {
+                            // handlers registered before on_init failed still run
+                            run_results.update_entity(&mut commands);
+                            // on_init doesn't run again on reload, so what it registers is kept
+                            match lua.run_event(EntityEvent::OnInit, LuaEntity { is_script_run: false, ..run_results }) {
+                                Ok(init_results) => init_results.update_entity(&mut commands),
+                                Err(e)           => lua.report_error(&format!("on_init for {:?}", entity), &e),
+                            }
+                        },
+                        Ok(run_results) => run_results.update_entity(&mut commands),
+                        Err(e)          => lua.report_error(&format!("{} for {:?}", script.path, entity), &e),
                     }
                 }
+
+                let mut event = GlobalEvent::new("entity_spawned").with_entity("entity", entity);
+                if let Some(pos) = pos {
+                    event = event.with_value("pos", pos.clone());
+                }
+                if let Err(e) = lua.run_global_event(event) {
+                    lua.report_error(&format!("global entity_spawned for {:?}", entity), &e);
+                }
+            }
+        }
+    });
+}
+
+/// Re-runs edited scripts for the entities already using them. Anything kept in `local_entity:data()` is left as it was
+/// unless the settings say otherwise, and `on_init` doesn't run again
+pub fn reload_scripts(
+    mut commands:      Commands,
+    mut lua:           ResMut<LuaResource>,
+    settings:          Res<ReloadSettings>,
+    scripts:           Res<Assets<LuaScript>>,
+    mut script_events: EventReader<AssetEvent<LuaScript>>,
+    query:             Query<(Entity, &Handle<LuaScript>)>,
+) {
+    for event in script_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            let script = match scripts.get(handle) {
+                Some(script) => script,
+                None         => continue,
+            };
+            println!("Reloading {}", script.path);
+            query.for_each(|(entity, entity_script)| {
+                if entity_script == handle {
+                    reload_script(&mut commands, &mut lua, &settings, script, entity);
+                }
+            });
+        }
+    }
+}
+
+/// Hands the shared libraries to Lua once they've all loaded, and again whenever one is edited. Every entity's script
+/// runs again after an edit, since any of them could have required the module, directly or through another one
+pub fn load_modules(
+    mut commands:      Commands,
+    mut lua:           ResMut<LuaResource>,
+    mut modules:       ResMut<LuaModules>,
+    settings:          Res<ReloadSettings>,
+    asset_server:      Res<AssetServer>,
+    scripts:           Res<Assets<LuaScript>>,
+    mut script_events: EventReader<AssetEvent<LuaScript>>,
+    query:             Query<(Entity, &Handle<LuaScript>)>,
+) {
+    if !modules.is_loaded {
+        if asset_server.get_group_load_state(modules.handles.iter().map(|h| h.id)) != LoadState::Loaded {
+            return;
+        }
+        for handle in modules.handles.iter() {
+            if let Some(script) = scripts.get(handle) {
+                if let Err(e) = lua.set_module(script) {
+                    lua.report_error(&script.path, &e);
+                }
             }
         }
+        modules.is_loaded = true;
+    }
+    for event in script_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if !modules.handles.iter().any(|h| h.id == handle.id) {
+                continue;
+            }
+            if let Some(script) = scripts.get(handle) {
+                // the old version is dropped from `package.loaded` here, so requiring it again picks up the edit
+                if let Err(e) = lua.set_module(script) {
+                    lua.report_error(&script.path, &e);
+                }
+                println!("Reloading every script for {}", script.path);
+                query.for_each(|(entity, entity_script)| {
+                    if let Some(entity_script) = scripts.get(entity_script) {
+                        reload_script(&mut commands, &mut lua, &settings, entity_script, entity);
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn reload_script(commands: &mut Commands, lua: &mut LuaResource, settings: &ReloadSettings, script: &LuaScript, entity: Entity) {
+    match lua.reload_script(script, entity, settings) {
+        Ok(run_results) => run_results.update_entity(commands),
+        Err(e)          => lua.report_error(&format!("{} for {:?}", script.path, entity), &e),
+    }
+}
+
+/// Runs `on_destroy` and the global `entity_died` for entities marked `ToDespawn`, then takes them out of their level and
+/// despawns them. This is the only place entities with scripts are despawned, so their handlers and data are dropped here
+pub fn despawn_marked(
+    mut commands: Commands,
+    mut lua:      ResMut<LuaResource>,
+    mut grids:    Query<&mut Grid>,
+    query:        Query<(Entity, Option<&Pos>, Option<&OwningLevel>, Option<&EnumSet<EntityEvent>>), With<ToDespawn>>,
+) {
+    query.for_each(|(entity, pos, owning_level, event_handlers)| {
+        if event_handlers.map_or(false, |h| h.contains(EntityEvent::OnDestroy)) {
+            if let Err(e) = lua.run_entity_event(EntityEvent::OnDestroy, LuaEntity::new(entity)) {
+                lua.report_error(&format!("on_destroy for {:?}", entity), &e);
+            }
+        }
+        let mut event = GlobalEvent::new("entity_died").with_entity("entity", entity);
+        if let Some(pos) = pos {
+            event = event.with_value("pos", pos.clone());
+        }
+        if let Err(e) = lua.run_global_event(event) {
+            lua.report_error(&format!("global entity_died for {:?}", entity), &e);
+        }
+        if let (Some(pos), Some(OwningLevel(level))) = (pos, owning_level) {
+            if let Ok(mut grid) = grids.get_mut(level.clone()) {
+                grid.remove_occupant(pos, entity);
+            }
+        }
+        if let Err(e) = lua.forget_entity(entity) {
+            lua.report_error(&format!("cleaning up {:?}", entity), &e);
+        }
+        commands.entity(entity).despawn();
     });
-}
\ No newline at end of file
+}
+
+/// Delivers messages queued by scripts in an earlier stage, before this stage's scripts run
+pub fn deliver_messages(
+    mut lua: ResMut<LuaResource>,
+) {
+    lua.deliver_queued_messages();
+}
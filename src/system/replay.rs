@@ -0,0 +1,109 @@
+use bevy::{
+    ecs::schedule::ShouldRun,
+    prelude::*,
+};
+use std::hash::{Hash, Hasher};
+
+use crate::data::action::*;
+use crate::data::level::*;
+use crate::data::replay::*;
+use crate::data::turn::*;
+use crate::util::hash::*;
+
+/// Run criteria for anything reading live input, which a replay takes the place of
+pub fn is_not_replaying(player: Option<Res<ReplayPlayer>>) -> ShouldRun {
+    if player.is_some() { ShouldRun::No } else { ShouldRun::Yes }
+}
+
+pub fn record_replay(
+    recorder:          Option<ResMut<ReplayRecorder>>,
+    turn_count:        Res<TurnCount>,
+    mut actions_taken: EventReader<ActionTaken>,
+    sources:           Query<&ActionSource>,
+) {
+    if let Some(mut recorder) = recorder {
+        for ActionTaken { entity, action, .. } in actions_taken.iter() {
+            // scripts choose the same actions again when the replay is played, so only input is recorded
+            if let Some(source) = sources.get(entity.clone()).ok().filter(|source| !source.is_scripted()) {
+                recorder.record(ReplayStep { turn: turn_count.0, source: source.clone(), action: action.clone() });
+            }
+        }
+    }
+}
+
+/// Rewrites the replay file at the start of every turn, so it's there even if the game crashes
+pub fn save_replay(
+    recorder:   Option<ResMut<ReplayRecorder>>,
+    turn_count: Res<TurnCount>,
+    positions:  Query<&Pos>,
+) {
+    if let Some(mut recorder) = recorder {
+        recorder.replay.checksum = state_checksum(&turn_count, &positions);
+        if let Err(e) = recorder.replay.save(&recorder.path) {
+            println!("Failed to save replay to {:?}: {}", recorder.path, e);
+        }
+    }
+}
+
+pub fn play_replay(
+    player:     Option<ResMut<ReplayPlayer>>,
+    turn_count: Res<TurnCount>,
+    mut fed:    Local<Option<usize>>, // the turn steps were last fed in for
+    mut query:  Query<(&ActionSource, &mut LocalActions)>,
+) {
+    let mut player = match player {
+        Some(player) => player,
+        None         => return,
+    };
+    // a fed action that didn't end the turn must have been blocked, so this run has gone differently
+    if *fed == Some(turn_count.0) && query.iter_mut().all(|(_, actions)| actions.queued.is_none()) {
+        println!("Replay diverged: a recorded action on turn {} could not be taken", turn_count.0);
+        std::process::exit(1);
+    }
+    while let Some(step) = player.replay.steps.get(player.next).cloned() {
+        if step.turn != turn_count.0 {
+            break;
+        }
+        let actions = query.iter_mut().find(|(source, _)| **source == step.source).map(|(_, actions)| actions);
+        match actions {
+            Some(mut actions) if player.is_fast || actions.move_timer.finished() => {
+                actions.queued = Some(step.action);
+                player.next += 1;
+                *fed = Some(turn_count.0);
+            },
+            // wait for the entity to spawn, or for the walking pace to catch up
+            _ => break,
+        }
+    }
+}
+
+/// Once every step has been played, compares where the run ended up with where the recording did
+pub fn check_replay(
+    player:     Option<Res<ReplayPlayer>>,
+    turn_count: Res<TurnCount>,
+    positions:  Query<&Pos>,
+) {
+    if let Some(player) = player {
+        let is_done = player.is_finished() && player.replay.steps.last().map_or(true, |step| turn_count.0 > step.turn);
+        if is_done {
+            let checksum = state_checksum(&turn_count, &positions);
+            if checksum == player.replay.checksum {
+                println!("Replay finished on turn {} with matching checksum {:x}", turn_count.0, checksum);
+                std::process::exit(0);
+            } else {
+                println!("Replay finished on turn {} with checksum {:x}, expected {:x}", turn_count.0, checksum, player.replay.checksum);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Summarizes the turn and where everything is, independent of entity ids and query order
+fn state_checksum(turn_count: &TurnCount, positions: &Query<&Pos>) -> u64 {
+    let mut sorted: Vec<Pos> = positions.iter().cloned().collect();
+    sorted.sort();
+    let mut hasher = StableHasher::default();
+    turn_count.0.hash(&mut hasher);
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
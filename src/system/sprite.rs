@@ -5,11 +5,11 @@ use crate::data::sprite::*;
 
 pub fn update_animations(
     time: Res<Time>,
-    query: Query<(&SpriteInfo, &mut AnimState, &mut TextureAtlasSprite)>,
+    query: Query<(&SpriteInfo, &mut AnimState, &mut TextureAtlasSprite, Option<&TintOverride>)>,
 ) {
-    query.for_each_mut(|(info, mut state, mut texture)| {
+    query.for_each_mut(|(info, mut state, mut texture, tint)| {
         state.update(info, time.borrow());
-        texture.color = state.cur_tint(info).color();
+        texture.color = tint.map_or_else(|| state.cur_tint(info), |t| t.0).color();
         texture.index = state.cur_index(info);
     });
 }
\ No newline at end of file
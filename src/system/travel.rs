@@ -0,0 +1,77 @@
+use bevy::{
+    prelude::*,
+    render::{camera::Camera, render_graph::base},
+};
+use enumset::*;
+use std::collections::VecDeque;
+
+use crate::data::action::*;
+use crate::data::level::*;
+use crate::data::player::*;
+use crate::data::sprite::TILE_SIZE;
+
+#[derive(Default)]
+pub struct TravelPreview {
+    material: Option<Handle<ColorMaterial>>,
+    hovered:  Option<(Pos, Pos)>, // cursor and player positions the markers were made for
+    path:     Option<VecDeque<Pos>>,
+    markers:  Vec<Entity>,
+}
+
+pub fn update_travel(
+    mut commands:  Commands,
+    mut preview:   Local<TravelPreview>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    time:          Res<Time>,
+    map_scale:     Res<MapScale>,
+    windows:       Res<Windows>,
+    mouse_input:   Res<Input<MouseButton>>,
+    cameras:       Query<(&Transform, &Camera)>,
+    grids:         Query<&Grid>,
+    changed_grids: Query<Entity, Changed<Grid>>,
+    mut players:   Query<(&Pos, &OwningLevel, &mut LocalActions, &ActionSource, Option<&EnumSet<Movement>>), With<Player>>,
+) {
+    let cursor_world = windows.get_primary().and_then(|window| {
+        let cursor = window.cursor_position()?;
+        let (camera, _) = cameras.iter().find(|(_, camera)| camera.name.as_deref() == Some(base::camera::CAMERA_2D))?;
+        let offset = cursor - Vec2::new(window.width(), window.height()) * 0.5;
+        Some(camera.compute_matrix() * offset.extend(0.).extend(1.))
+    });
+
+    let preview = &mut *preview;
+    // only the first keyboard player follows the cursor
+    if let Some((pos, OwningLevel(level_entity), mut actions, _, movement)) = players.iter_mut().find(|(_, _, _, source, _)| **source == ActionSource::Keyboard) {
+        let grid = match grids.get(level_entity.clone()) {
+            Ok(grid) => grid,
+            Err(_)   => return,
+        };
+        let hovered = cursor_world.map(|w| Pos::from_translation(w.truncate(), &map_scale, pos.z));
+        if hovered.map(|h| (h, pos.clone())) != preview.hovered || changed_grids.get(level_entity.clone()).is_ok() {
+            preview.hovered = hovered.map(|h| (h, pos.clone()));
+            preview.path    = hovered.and_then(|h| grid.find_path(pos, &h, movement.cloned().unwrap_or_default()));
+            for marker in preview.markers.drain(..) {
+                commands.entity(marker).despawn();
+            }
+            if let Some(path) = &preview.path {
+                let material = preview.material.get_or_insert_with(|| materials.add(Color::rgba(1., 0.969, 0.894, 0.35).into())).clone();
+                let size = Vec2::splat(0.5 * TILE_SIZE * map_scale.0);
+                let markers: Vec<Entity> = path.iter().map(|p| {
+                    commands.spawn_bundle(SpriteBundle {
+                        material: material.clone(),
+                        sprite:   Sprite::new(size),
+                        visible:  Visible { is_visible: true, is_transparent: true },
+                        transform: Transform::from_translation(p.translation(&map_scale) + Vec3::new(0., 0., 0.5)),
+                        ..Default::default()
+                    }).id()
+                }).collect();
+                preview.markers = markers;
+            }
+        }
+
+        if mouse_input.just_pressed(MouseButton::Left) {
+            if let Some(path) = preview.path.clone() {
+                actions.start_travel(path, time.seconds_since_startup());
+            }
+        }
+    }
+}
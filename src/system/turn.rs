@@ -1,23 +1,165 @@
 use bevy::{
+    ecs::schedule::ShouldRun,
     prelude::*,
 };
 use enumset::*;
 
+use crate::data::action::*;
 use crate::data::turn::*;
 use crate::lua::*;
+use crate::lua::vars::*;
 
-pub fn update_turn(
-    turn_count: Res<TurnCount>,
-    mut lua:    ResMut<LuaResource>,
-    query_set:  QuerySet<(
-        Query<(Entity, &EnumSet<EntityEvent>)>,
-    )>,
+pub fn in_turn_start(phase: Res<TurnPhase>) -> ShouldRun { phase.should_run(TurnPhase::Start) }
+pub fn in_player_action(phase: Res<TurnPhase>) -> ShouldRun { phase.should_run(TurnPhase::PlayerAction) }
+pub fn in_npc_actions(phase: Res<TurnPhase>) -> ShouldRun { phase.should_run(TurnPhase::NpcActions) }
+pub fn in_environment(phase: Res<TurnPhase>) -> ShouldRun { phase.should_run(TurnPhase::Environment) }
+pub fn in_turn_end(phase: Res<TurnPhase>) -> ShouldRun { phase.should_run(TurnPhase::End) }
+
+pub fn start_turn(
+    mut phase: ResMut<TurnPhase>,
+    mut lua:   ResMut<LuaResource>,
+    query:     Query<(Entity, &EnumSet<EntityEvent>)>,
 ) {
-    if turn_count.is_changed() {
-        query_set.q0().for_each(|(entity, event_handlers)| {
-            if event_handlers.contains(EntityEvent::OnUpdate) {
-                lua.run_event(EntityEvent::OnUpdate, LuaEntity::new(entity)).unwrap();
-            }
+    run_turn_event(&mut lua, &query, "turn_start", EntityEvent::OnTurnStart);
+    *phase = phase.next();
+}
+
+/// In real time, anyone controlled who hasn't acted by the time the turn's timer runs out waits instead
+pub fn update_turn_mode(
+    time:           Res<Time>,
+    controls:       Res<ControlSettings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    buttons:        Res<Input<GamepadButton>>,
+    turn_count:     Res<TurnCount>,
+    mut mode:       ResMut<TurnMode>,
+    mut last_turn:  Local<usize>,
+    mut query:      Query<(&ActionSource, &mut LocalActions)>,
+) {
+    if let TurnMode::RealTime { timer, is_paused } = &mut *mode {
+        let pad_pressed = query.iter_mut().any(|(source, _)| match source {
+            ActionSource::Gamepad(idx) => buttons.just_pressed(GamepadButton(Gamepad(idx.clone()), controls.pad_pause)),
+            _ => false,
         });
+        if keyboard_input.just_pressed(controls.pause) || pad_pressed {
+            *is_paused = !*is_paused;
+        }
+        if turn_count.0 != *last_turn {
+            *last_turn = turn_count.0;
+            timer.reset();
+        }
+        if *is_paused {
+            return;
+        }
+        timer.tick(time.delta());
+        if timer.finished() {
+            query.for_each_mut(|(_, mut actions)| {
+                if actions.queued.is_none() {
+                    actions.queued = Some(Action::Wait);
+                }
+            });
+        }
+    }
+}
+
+/// Waits for a controlled entity to use its turn, then hands over to the NPCs
+pub fn end_player_action(
+    mut phase:         ResMut<TurnPhase>,
+    mut actions_taken: EventReader<ActionTaken>,
+    mut query:         Query<&mut Energy>,
+) {
+    let mut has_acted = false;
+    for ActionTaken { entity, cost, .. } in actions_taken.iter() {
+        if let Ok(mut energy) = query.get_mut(entity.clone()) {
+            energy.spend(cost.clone());
+        }
+        has_acted = true;
+    }
+    if has_acted {
+        *phase = phase.next();
+    }
+}
+
+/// NPCs act in initiative order until a player-controlled entity has the energy to act again. Entities whose actions
+/// come from scripts are NPCs here, and do whatever was chosen for them through `act` once their `on_update` has run
+pub fn run_npc_actions(
+    mut phase: ResMut<TurnPhase>,
+    mut lua:   ResMut<LuaResource>,
+    mut query: Query<(Entity, &mut Energy, Option<&ActionSource>, Option<&EnumSet<EntityEvent>>)>,
+) {
+    let mut actors: Vec<Actor> = query.iter_mut()
+        .map(|(entity, energy, source, _)| Actor { entity, energy: *energy, is_controlled: source.map_or(false, |s| !s.is_scripted()) })
+        .collect();
+    take_npc_turns(&mut actors, |entity| {
+        let (_, _, _, event_handlers) = query.get_mut(entity).unwrap();
+        let mut cost = ACTION_COST;
+        if event_handlers.map_or(false, |h| h.contains(EntityEvent::OnUpdate)) {
+            match lua.run_entity_event(EntityEvent::OnUpdate, LuaEntity::new(entity)) {
+                Ok(result) => cost = result.action_cost.unwrap_or(ACTION_COST),
+                Err(e)     => lua.report_error(&format!("on_update for {:?}", entity), &e),
+            }
+        }
+        lua.global.world.lock().take_action(entity);
+        cost
+    });
+    for actor in actors {
+        if let Ok((_, mut energy, _, _)) = query.get_mut(actor.entity) {
+            *energy = actor.energy;
+        }
     }
-}
\ No newline at end of file
+    *phase = phase.next();
+}
+
+/// Anything scripted that doesn't take turns of its own updates once per turn
+pub fn run_environment(
+    mut phase: ResMut<TurnPhase>,
+    mut lua:   ResMut<LuaResource>,
+    query:     Query<(Entity, &EnumSet<EntityEvent>), Without<Energy>>,
+) {
+    query.for_each(|(entity, event_handlers)| {
+        if event_handlers.contains(EntityEvent::OnUpdate) {
+            if let Err(e) = lua.run_entity_event(EntityEvent::OnUpdate, LuaEntity::new(entity)) {
+                lua.report_error(&format!("on_update for {:?}", entity), &e);
+            }
+        }
+    });
+    *phase = phase.next();
+}
+
+pub fn end_turn(
+    mut phase:      ResMut<TurnPhase>,
+    mut turn_count: ResMut<TurnCount>,
+    mut lua:        ResMut<LuaResource>,
+    query:          Query<(Entity, &EnumSet<EntityEvent>)>,
+) {
+    run_turn_event(&mut lua, &query, "turn_end", EntityEvent::OnTurnEnd);
+    advance_turn(&mut turn_count, &mut lua);
+    *phase = phase.next();
+}
+
+/// Runs the global event `key` with the turn number, then the entity event for everything that registered it
+fn run_turn_event(lua: &mut LuaResource, query: &Query<(Entity, &EnumSet<EntityEvent>)>, key: &str, event: EntityEvent) {
+    let turn = lua.global.turn_count as i64;
+    if let Err(e) = lua.run_global_event(GlobalEvent::new(key).with_value("turn", turn)) {
+        lua.report_error(&format!("global {}", key), &e);
+    }
+    query.for_each(|(entity, event_handlers)| {
+        if event_handlers.contains(event) {
+            if let Err(e) = lua.run_entity_event(event, LuaEntity::new(entity)) {
+                lua.report_error(&format!("on_{} for {:?}", key, entity), &e);
+            }
+        }
+    });
+}
+
+/// Writes script variables out at the start of every turn, for `--continue` to pick up
+pub fn save_vars(lua: Res<LuaResource>) {
+    if let Err(e) = lua.global.vars.save(VARS_PATH.as_ref()) {
+        println!("Failed to save variables to {}: {}", VARS_PATH, e);
+    }
+}
+
+pub fn advance_turn(turn_count: &mut TurnCount, lua: &mut LuaResource) {
+    turn_count.0 += 1;
+    lua.global.turn_count += 1;
+    lua.sync();
+}
@@ -0,0 +1,12 @@
+use bevy::prelude::*;
+
+use crate::data::tween::*;
+
+pub fn update_tweens(
+    time:  Res<Time>,
+    query: Query<&mut Tween>,
+) {
+    query.for_each_mut(|mut tween| {
+        tween.timer.tick(time.delta());
+    });
+}
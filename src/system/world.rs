@@ -0,0 +1,178 @@
+use bevy::{
+    prelude::*,
+};
+use enumset::*;
+use std::collections::HashMap;
+
+use crate::data::action::*;
+use crate::data::level::*;
+use crate::data::player::*;
+use crate::data::prefab::*;
+use crate::data::sprite::*;
+use crate::data::tween::*;
+use crate::lua::*;
+use crate::lua::world::*;
+use crate::system::player::*;
+
+/// Copies whatever changed since this last ran into the view scripts see
+pub fn sync_world_view(
+    lua:              Res<LuaResource>,
+    entities:         Query<(Entity, &Pos, &OwningLevel, Option<&EnumSet<Movement>>), Or<(Changed<Pos>, Changed<OwningLevel>, Changed<EnumSet<Movement>>)>>,
+    grids:            Query<(Entity, &Grid), Changed<Grid>>,
+    levels:           Query<(Entity, &LevelInfo), Changed<LevelInfo>>,
+    players:          Query<Entity, With<Player>>,
+    sources:          Query<(Entity, &ActionSource), Changed<ActionSource>>,
+    sprites:          Query<(Entity, &SpriteInfo, &AnimState), Changed<AnimState>>,
+    removed_entities: RemovedComponents<Pos>,
+    removed_grids:    RemovedComponents<Grid>,
+) {
+    let mut world = lua.global.world.lock();
+    entities.for_each(|(entity, pos, OwningLevel(level), movement)| {
+        world.entities.insert(entity, EntityView { pos: pos.clone(), level: level.clone(), movement: movement.cloned().unwrap_or_default() });
+    });
+    grids.for_each(|(entity, grid)| {
+        world.grids.insert(entity, grid.clone());
+    });
+    levels.for_each(|(entity, info)| {
+        world.levels.insert(entity, info.clone());
+    });
+    world.players = players.iter().collect();
+    world.players.sort_by_key(|e| e.id());
+    sources.for_each(|(entity, source)| {
+        world.sources.insert(entity, source.clone());
+    });
+    sprites.for_each(|(entity, info, state)| {
+        let view = world.sprites.entry(entity).or_insert_with(|| SpriteView {
+            name_to_index: match &info.anim {
+                AnimInfo::Animated {name_to_index, ..} => name_to_index.clone(),
+                AnimInfo::Static {..} => HashMap::new(),
+            },
+            cur_anim_idx: 0,
+        });
+        view.cur_anim_idx = state.cur_anim_idx;
+    });
+    for entity in removed_entities.iter() {
+        world.entities.remove(&entity);
+        world.sprites.remove(&entity);
+        world.sources.remove(&entity);
+    }
+    for entity in removed_grids.iter() {
+        world.grids.remove(&entity);
+        world.levels.remove(&entity);
+    }
+}
+
+/// Tops up the entities `global:spawn` hands out, ahead of the scripts that use them
+pub fn refill_entity_pool(
+    mut commands: Commands,
+    lua:          Res<LuaResource>,
+) {
+    let mut world = lua.global.world.lock();
+    while world.entity_pool.len() < ENTITY_POOL_SIZE {
+        world.entity_pool.push(commands.spawn().id());
+    }
+}
+
+/// Applies moves made by scripts to the components they mirror
+pub fn apply_world_view(
+    mut commands:    Commands,
+    mut lua:         ResMut<LuaResource>,
+    asset_server:    Res<AssetServer>,
+    map_scale:       Res<MapScale>,
+    mut grid_events: EventWriter<GridChanged>,
+    mut query:       Query<(&mut Pos, Option<&Tween>)>,
+    mut grids:       Query<&mut Grid>,
+    mut sprites:     Query<(&mut TextureAtlasSprite, &mut Visible, Option<&SpriteInfo>, Option<&mut AnimState>)>,
+    event_handlers:  Query<&EnumSet<EntityEvent>>,
+    players:         Query<(), With<Player>>,
+    not_despawning:  Query<(), Without<ToDespawn>>,
+) {
+    let (spawns, despawns, relocated, sprite_changes, tile_changes, registered) = {
+        let mut world = lua.global.world.lock();
+        (
+            std::mem::take(&mut world.spawns),
+            std::mem::take(&mut world.despawns),
+            std::mem::take(&mut world.relocated),
+            std::mem::take(&mut world.sprite_changes),
+            std::mem::take(&mut world.tile_changes),
+            std::mem::take(&mut world.registered),
+        )
+    };
+    for change in tile_changes {
+        if let Ok(mut grid) = grids.get_mut(change.level) {
+            grid.set(&change.pos, change.state.clone());
+            grid_events.send(change);
+        }
+    }
+    // spawned the same way as entities placed in the level, spawn_prefab takes it from here
+    for Spawn { entity, level, pos, prefab } in spawns {
+        let corner = pos.translation(&map_scale) - Vec3::new(0.5 * TILE_SIZE * map_scale.0, -0.5 * TILE_SIZE * map_scale.0, 0.);
+        commands.entity(entity)
+            .insert(OwningLevel(level))
+            .insert(PrefabToSpawn { prefab: asset_server.load(prefab.as_str()), translation: corner })
+            .insert(pos);
+        if let Ok(mut grid) = grids.get_mut(level) {
+            grid.add_occupant(&pos, entity, EnumSet::empty());
+        }
+    }
+    for (entity, events) in registered {
+        if not_despawning.get(entity).is_err() {
+            continue;
+        }
+        let current = event_handlers.get(entity).map_or(EnumSet::empty(), |h| *h);
+        commands.entity(entity).insert(current | events);
+    }
+    // the view can be behind, so anything already gone, or already on its way out, is left alone
+    for entity in despawns {
+        if not_despawning.get(entity).is_err() {
+            continue;
+        }
+        commands.entity(entity).insert(ToDespawn);
+    }
+    let mut player_moves = Vec::new();
+    for Relocated { entity, level, from, to, movement, kind } in relocated {
+        if let Ok(mut grid) = grids.get_mut(level) {
+            grid.remove_occupant(&from, entity);
+            grid.add_occupant(&to, entity, movement);
+        }
+        if let Ok((mut pos, tween)) = query.get_mut(entity) {
+            let from_translation = match tween {
+                Some(tween) if tween.is_playing() => tween.ground_translation(),
+                _ => pos.translation(&map_scale),
+            };
+            *pos = to;
+            match kind {
+                Relocation::Step(_) => { commands.entity(entity).insert(Tween::hop(from_translation, to.translation(&map_scale), SECONDS_TO_WALK, &map_scale)); },
+                Relocation::Teleport => { commands.entity(entity).remove::<Tween>(); },
+            }
+            if players.get(entity).is_ok() {
+                player_moves.push((entity, from, to));
+            }
+        }
+    }
+    for (entity, change) in sprite_changes {
+        if let Ok((mut texture, mut visible, info, anim)) = sprites.get_mut(entity) {
+            match (change, info, anim) {
+                (SpriteChange::Anim(anim_idx), Some(info), Some(mut anim)) => {
+                    anim.set_anim(anim_idx, info);
+                    texture.index = anim.cur_index(info);
+                },
+                (SpriteChange::Frame(frame_idx), Some(info), Some(mut anim)) => {
+                    anim.set_frame(frame_idx as usize, info);
+                    texture.index = anim.cur_index(info);
+                },
+                (SpriteChange::Frame(index), _, _) => texture.index = index,
+                (SpriteChange::Tint(palette), _, _) => {
+                    texture.color = palette.color();
+                    commands.entity(entity).insert(TintOverride(palette));
+                },
+                (SpriteChange::Visible(is_visible), _, _) => visible.is_visible = is_visible,
+                (SpriteChange::Anim(_), _, _) => (),
+            }
+        }
+    }
+    // anything the handlers change is applied the next time this runs
+    for (entity, from, to) in player_moves {
+        notify_player_moved(&mut lua, entity, from, to);
+    }
+}
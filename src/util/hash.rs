@@ -0,0 +1,22 @@
+use std::hash::Hasher;
+
+/// FNV-1a, for hashes that must come out the same between runs and builds, which the standard library's hasher doesn't promise
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> StableHasher {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ byte.clone() as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
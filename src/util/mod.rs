@@ -1,2 +1,3 @@
+pub mod hash;
 pub mod serde;
 pub mod types;
\ No newline at end of file